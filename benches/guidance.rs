@@ -0,0 +1,86 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+//! Compare the time taken to generate the guidance field by blurring an
+//! upscaled image of the track (the original approach) with the signed
+//! distance transform that replaced it.
+//!
+//! Run with `cargo bench --bench guidance`.
+
+#[path = "../src/guidance.rs"]
+#[allow(dead_code)]
+mod guidance;
+#[path = "../src/track.rs"]
+mod track;
+#[path = "../src/util.rs"]
+#[allow(dead_code)]
+mod util;
+
+use itertools::Itertools;
+use std::{
+    hint::black_box,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+const LEVELS: [&str; 2] = ["src/assets/level1.tmx", "src/assets/level2.tmx"];
+
+/// The original generator: upscale the track layer to 128 pixels per tile
+/// and blur it.
+fn blurred_field(map: &tiled::Map) -> Option<image::GrayImage> {
+    let layer = map
+        .get_layer(1)
+        .unwrap_or(map.get_layer(0)?)
+        .as_tile_layer()?;
+
+    let w = layer.width()?;
+    let h = layer.height()?;
+
+    let micro_map = (0..h)
+        .cartesian_product(0..w)
+        .map(|(y, x)| layer.get_tile(x as i32, y as i32).is_some() as u8 * 255)
+        .collect::<Vec<u8>>();
+    let micro_map = image::GrayImage::from_vec(w, h, micro_map)?;
+
+    let mini_map = image::imageops::resize(
+        &micro_map,
+        w * 8,
+        h * 8,
+        image::imageops::FilterType::Nearest,
+    );
+    let mini_field = image::imageops::blur(&mini_map, 8.0);
+
+    Some(image::imageops::resize(
+        &mini_field,
+        w * 128,
+        h * 128,
+        image::imageops::FilterType::Gaussian,
+    ))
+}
+
+/// Average time taken by `f`.
+fn time<T>(iterations: u32, mut f: impl FnMut() -> T) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        black_box(f());
+    }
+    start.elapsed() / iterations
+}
+
+fn main() {
+    for level in LEVELS {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(level);
+        let map = tiled::Loader::new()
+            .load_tmx_map(&path)
+            .expect("Cannot load map");
+
+        let old = time(3, || blurred_field(&map).expect("Cannot blur track"));
+        let new = time(100, || {
+            guidance::GuidanceField::from_map(&map).expect("Cannot generate guidance field")
+        });
+        println!(
+            "{level}: blur {old:?}, distance transform {new:?} ({:.0}x faster)",
+            old.as_secs_f64() / new.as_secs_f64()
+        );
+    }
+}
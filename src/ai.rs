@@ -0,0 +1,724 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+#![allow(clippy::type_complexity)]
+
+use bevy::{log, prelude::*};
+use std::f32::consts::PI;
+
+use crate::{
+    damage,
+    geometry::Polygon,
+    guidance, hazard,
+    menu::{Difficulty, RubberBanding},
+    physics,
+    racing_line::{DrivingStyle, RacingLine},
+    respawn, GameState, Player, Preferences, Racer, RACE_LAPS,
+};
+
+/// Gap to the player (in world units) at which the rubber-banding reaches
+/// full strength.
+const RUBBER_BAND_RANGE: f32 = 3000.0;
+
+/// Largest change to the pace of the AI made by the rubber-banding.
+const RUBBER_BAND_STRENGTH: f32 = 0.08;
+
+/// Width of the area scanned for other cars (a little wider than a car).
+const SENSOR_WIDTH: f32 = 90.0;
+
+/// Distance from the centre of the car to its bumpers and to its sides.
+const HALF_CAR_LENGTH: f32 = 65.0;
+const HALF_CAR_WIDTH: f32 = 35.0;
+
+/// How far to move away from a car that is alongside.
+const SIDE_BY_SIDE_OFFSET: f32 = 90.0;
+
+/// How far to the side of the racing line to go to overtake.
+const OVERTAKE_OFFSET: f32 = 110.0;
+
+/// How far to move across to defend against a car behind.
+const DEFEND_OFFSET: f32 = 45.0;
+
+/// Rate (world units per second) at which a driver moves across the track.
+const OFFSET_RATE: f32 = 150.0;
+
+/// A car that is trying to drive but has moved less than `STUCK_DISTANCE`
+/// in `STUCK_TIME` seconds, and never gone faster than `STUCK_SPEED`, is
+/// stuck.
+const STUCK_SPEED: f32 = 40.0;
+const STUCK_TIME: f32 = 2.0;
+const STUCK_DISTANCE: f32 = 60.0;
+
+/// How long, in seconds, to reverse for when trying to get unstuck.
+const RECOVERY_TIME: f32 = 1.2;
+
+/// Number of times to try reversing out of trouble before giving up and
+/// going back to the last checkpoint.
+const MAX_RECOVERIES: u32 = 2;
+
+#[derive(Default)]
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LevelDifficulty>()
+            .add_systems(OnEnter(GameState::NextLevel), reset_level_difficulty)
+            .add_systems(
+                Update,
+                (
+                    assign_drivers,
+                    rubber_band
+                        .after(assign_drivers)
+                        .before(handle_ai_players)
+                        .run_if(in_state(GameState::Game)),
+                    sense_traffic
+                        .after(assign_drivers)
+                        .before(handle_ai_players)
+                        .run_if(in_state(GameState::Game)),
+                ),
+            );
+    }
+}
+
+/// Difficulty requested by the current level, which overrides the menu
+/// setting.
+#[derive(Resource, Debug, Default)]
+pub struct LevelDifficulty(pub Option<Difficulty>);
+
+/// The personality of an AI driver.
+#[derive(Clone, Copy, Debug)]
+pub struct Skill {
+    /// Time (in seconds) taken to respond to the track ahead.
+    pub reaction: f32,
+    /// How hard the driver pushes in the corners (1.0 follows the racing
+    /// line speeds exactly).
+    pub cornering: f32,
+    /// How far (in world units) the driver wanders from the racing line.
+    pub line_error: f32,
+    /// Scale factor for the speed on the straights.
+    pub top_speed: f32,
+}
+
+impl Skill {
+    pub fn preset(difficulty: Difficulty) -> Self {
+        match difficulty {
+            Difficulty::Easy => Self {
+                reaction: 0.35,
+                cornering: 0.85,
+                line_error: 60.0,
+                top_speed: 0.88,
+            },
+            Difficulty::Normal => Self {
+                reaction: 0.2,
+                cornering: 0.95,
+                line_error: 30.0,
+                top_speed: 0.95,
+            },
+            Difficulty::Hard => Self {
+                reaction: 0.08,
+                cornering: 1.05,
+                line_error: 10.0,
+                top_speed: 1.0,
+            },
+        }
+    }
+
+    /// Give each driver a slightly different take on the preset so the
+    /// field spreads out.
+    fn vary(self) -> Self {
+        let jitter = |v: f32| v * (0.9 + 0.2 * fastrand::f32());
+        Self {
+            reaction: jitter(self.reaction),
+            cornering: jitter(self.cornering),
+            line_error: jitter(self.line_error),
+            top_speed: jitter(self.top_speed).min(1.0),
+        }
+    }
+}
+
+/// The state of an AI driver.
+#[derive(Component, Debug)]
+pub struct Driver {
+    skill: Skill,
+    difficulty: Difficulty,
+    steer: f32,
+    throttle: f32,
+    /// Keeps the drivers from all wandering off the line in step.
+    phase: f32,
+    /// Adjustment to the pace from the rubber-banding.
+    pace: f32,
+    /// Distance from the racing line needed to get past (or hold off) other
+    /// cars, and how far across we have moved so far.
+    traffic_offset: f32,
+    offset: f32,
+    /// Speed of the car we are stuck behind.
+    speed_limit: Option<f32>,
+    /// Where we were when we started to look stuck, and for how long.
+    stuck_at: Vec2,
+    stuck_time: f32,
+    /// Time left reversing out of trouble.
+    reversing: f32,
+    /// Attempts to get unstuck since we were last moving freely.
+    recoveries: u32,
+}
+
+impl Driver {
+    fn new(difficulty: Difficulty) -> Self {
+        Self {
+            skill: Skill::preset(difficulty).vary(),
+            difficulty,
+            steer: 0.0,
+            throttle: 0.0,
+            phase: 2.0 * PI * fastrand::f32(),
+            pace: 1.0,
+            traffic_offset: 0.0,
+            offset: 0.0,
+            speed_limit: None,
+            stuck_at: Vec2::ZERO,
+            stuck_time: 0.0,
+            reversing: 0.0,
+            recoveries: 0,
+        }
+    }
+}
+
+fn reset_level_difficulty(mut level: ResMut<LevelDifficulty>) {
+    level.0 = None;
+}
+
+/// Give every AI car a driver (and a new one if the difficulty changes).
+///
+/// Cars taken over by a gamepad keep their driver so they carry on as
+/// before when they are handed back.
+fn assign_drivers(
+    mut commands: Commands,
+    mut cars: Query<(Entity, Option<&mut Driver>), (With<Racer>, Without<Player>)>,
+    difficulty: Res<Difficulty>,
+    level: Res<LevelDifficulty>,
+) {
+    let difficulty = level.0.unwrap_or(*difficulty);
+
+    for (car, driver) in cars.iter_mut() {
+        match driver {
+            Some(driver) if driver.difficulty == difficulty => (),
+            Some(mut driver) => *driver = Driver::new(difficulty),
+            None => {
+                log::debug!("{car:?} driving at {difficulty:?} difficulty");
+                commands.entity(car).insert(Driver::new(difficulty));
+            }
+        }
+    }
+}
+
+/// Slow down AI cars that are a long way ahead of the lead player and speed
+/// up those that are a long way behind.
+fn rubber_band(
+    players: Query<(&Player, &Racer), Without<Driver>>,
+    mut drivers: Query<(&mut Driver, &Racer), Without<Player>>,
+    line: Option<Res<RacingLine>>,
+    enabled: Res<RubberBanding>,
+) {
+    let (Some(line), true) = (line, enabled.0) else {
+        for (mut driver, _) in drivers.iter_mut() {
+            driver.pace = 1.0;
+        }
+        return;
+    };
+
+    let Some((_, player)) = players.iter().find(|(p, _)| p.is_lead()) else {
+        return;
+    };
+
+    for (mut driver, racer) in drivers.iter_mut() {
+        let gap = line.gap(racer.waypoint, player.waypoint) / RUBBER_BAND_RANGE;
+        driver.pace = 1.0 - RUBBER_BAND_STRENGTH * gap.clamp(-1.0, 1.0);
+    }
+}
+
+/// A box, `width` across and `length` long, reaching out `from` the centre
+/// of the car in the direction of `dir`.
+fn sensor(pos: Vec2, dir: Vec2, from: f32, width: f32, length: f32) -> Polygon {
+    let xform = Transform {
+        translation: (pos + dir * (from + length / 2.0)).extend(0.0),
+        rotation: Quat::from_rotation_z(dir.y.atan2(dir.x) - PI / 2.0),
+        scale: Vec3::ONE,
+    };
+    Polygon::from_vec(&Vec2::new(width, length)).transform(&xform)
+}
+
+/// Look out for other cars.
+///
+/// Each driver scans the road ahead for slower cars. If there is one they
+/// pull out to pass on whichever side has the most room (or lift off if
+/// there is no room at all). They also give room to cars that are alongside,
+/// keep an eye on the mirrors, and move across to defend against faster cars
+/// coming up from behind.
+fn sense_traffic(
+    mut drivers: Query<(
+        Entity,
+        &mut Driver,
+        &Transform,
+        &physics::Velocity,
+        &physics::Angle,
+    )>,
+    cars: Query<
+        (
+            Entity,
+            &physics::CollisionBox,
+            &Transform,
+            &physics::Velocity,
+        ),
+        With<Racer>,
+    >,
+    guide: Option<Res<guidance::GuidanceField>>,
+    prefs: Res<Preferences>,
+    mut gizmos: Gizmos,
+) {
+    let has_room = |pt: Vec2| guide.as_ref().is_none_or(|g| g.get(&pt) > 100);
+
+    for (me, mut driver, xform, v, a) in drivers.iter_mut() {
+        let pos = xform.translation.truncate();
+        let heading = Vec2::from_angle(a.0);
+        let left = heading.perp();
+        let speed = v.0.dot(heading);
+
+        let ahead = sensor(
+            pos,
+            heading,
+            HALF_CAR_LENGTH,
+            SENSOR_WIDTH,
+            150.0 + 0.6 * speed.max(0.0),
+        );
+        let behind = sensor(pos, -heading, HALF_CAR_LENGTH, SENSOR_WIDTH, 200.0);
+        let beside =
+            [left, -left].map(|dir| sensor(pos, dir, HALF_CAR_WIDTH, 2.0 * HALF_CAR_LENGTH, 40.0));
+        if prefs.debug_high() {
+            for s in [&ahead, &behind, &beside[0], &beside[1]] {
+                s.draw(&mut gizmos);
+            }
+        }
+
+        let mut target = 0.0;
+        let mut speed_limit = None;
+        let mut blocked = false;
+        for (other, physics::CollisionBox(poly), other_xform, other_v) in cars.iter() {
+            if other == me {
+                continue;
+            }
+            let other_box = poly.transform(other_xform);
+            let other_pos = other_xform.translation.truncate();
+            let lateral = (other_pos - pos).dot(left);
+            let closing = (v.0 - other_v.0).dot(heading);
+
+            if closing > 0.0 && ahead.is_touching(&other_box) {
+                // Pass on the opposite side to the one the other car is on
+                // (so long as that doesn't put us off the track)
+                let preferred = if lateral > 0.0 { -1.0 } else { 1.0 };
+                let side = [preferred, -preferred]
+                    .into_iter()
+                    .find(|side| has_room(other_pos + left * *side * OVERTAKE_OFFSET));
+                match side {
+                    Some(side) => target = side * OVERTAKE_OFFSET,
+                    None => speed_limit = Some(other_v.0.dot(heading)),
+                }
+                blocked = true;
+            } else if blocked {
+                continue;
+            } else if beside[0].is_touching(&other_box) {
+                // Give a car alongside some room
+                target = -SIDE_BY_SIDE_OFFSET;
+            } else if beside[1].is_touching(&other_box) {
+                target = SIDE_BY_SIDE_OFFSET;
+            } else if closing < 0.0 && behind.is_touching(&other_box) {
+                // Cover the side the faster car is trying to come through
+                target = lateral.signum() * DEFEND_OFFSET;
+            }
+        }
+
+        driver.traffic_offset = target;
+        driver.speed_limit = speed_limit;
+    }
+}
+
+pub fn handle_ai_players(
+    mut query: Query<(
+        Entity,
+        &mut physics::Angle,
+        &mut physics::Velocity,
+        &mut Transform,
+        &mut Racer,
+        &mut Driver,
+        Option<&damage::Damage>,
+        Option<&hazard::Slipping>,
+        Without<Player>,
+    )>,
+    time: Res<Time>,
+    line: Option<Res<RacingLine>>,
+    guide: Option<Res<guidance::GuidanceField>>,
+    prefs: Res<Preferences>,
+    mut gizmos: Gizmos,
+    mut respawns: EventWriter<respawn::Respawn>,
+) {
+    let delta = time.delta_seconds();
+
+    if prefs.debug_high() {
+        if let Some(line) = &line {
+            line.draw(&mut gizmos);
+        }
+    }
+
+    for (car, mut a, mut v, mut t, mut r, mut driver, damage, slipping, _) in query.iter_mut() {
+        if r.lap_count >= RACE_LAPS {
+            continue;
+        }
+        if r.penalty > 0.0 {
+            r.penalty = if r.penalty < delta {
+                0.0
+            } else {
+                r.penalty - delta
+            };
+            continue;
+        }
+        let pos = Vec2::new(t.translation.x, t.translation.y);
+        let skill = driver.skill;
+
+        // Ease across towards the line needed to get round the traffic
+        let step = OFFSET_RATE * delta;
+        driver.offset += (driver.traffic_offset - driver.offset).clamp(-step, step);
+
+        // Follow the racing line if we have one, otherwise fall back to
+        // feeling our way round using the whiskers.
+        let (steer, throttle) = if let Some(line) = &line {
+            let style = DrivingStyle {
+                offset: driver.offset
+                    + skill.line_error * (0.4 * time.elapsed_seconds() + driver.phase).sin(),
+                cornering: skill.cornering * driver.pace,
+                top_speed: skill.top_speed * driver.pace,
+            };
+            line.drive(r.waypoint, pos, a.0, v.0, &style)
+        } else if let Some(guide) = &guide {
+            let (steer, throttle) =
+                whisker_controls(guide, pos, a.0, prefs.debug_high().then_some(&mut gizmos));
+            (steer, throttle * skill.top_speed * driver.pace)
+        } else {
+            return;
+        };
+
+        // Lift off rather than run into the back of a car we can't pass
+        let speed = v.0.dot(Vec2::from_angle(a.0));
+        let throttle = match driver.speed_limit {
+            Some(limit) if speed > limit => 0.0,
+            _ => throttle,
+        };
+
+        let (steer, throttle) = if driver.reversing > 0.0 {
+            // Back away, swinging the nose round towards where we want to go
+            driver.reversing -= delta;
+            (if steer < 0.0 { -1.0 } else { 1.0 }, -0.6)
+        } else {
+            (steer, throttle)
+        };
+
+        if driver.reversing <= 0.0 && speed.abs() < STUCK_SPEED && driver.throttle > 0.5 {
+            if driver.stuck_time == 0.0 {
+                driver.stuck_at = pos;
+            }
+            driver.stuck_time += delta;
+            if driver.stuck_time > STUCK_TIME {
+                driver.stuck_time = 0.0;
+                if pos.distance(driver.stuck_at) < STUCK_DISTANCE {
+                    if driver.recoveries < MAX_RECOVERIES || r.last_checkpoint.is_none() {
+                        log::debug!("AI car stuck at {pos}, reversing");
+                        driver.recoveries += 1;
+                        driver.reversing = RECOVERY_TIME;
+                    } else {
+                        log::debug!("AI car stuck at {pos}, respawning");
+                        driver.recoveries = 0;
+                        driver.steer = 0.0;
+                        driver.throttle = 0.0;
+                        respawns.send(respawn::Respawn { car });
+                        continue;
+                    }
+                }
+            }
+        } else if driver.reversing <= 0.0 {
+            driver.stuck_time = 0.0;
+            if speed > 4.0 * STUCK_SPEED {
+                driver.recoveries = 0;
+            }
+        }
+
+        // The driver takes a little while to respond
+        let response = (delta / skill.reaction.max(delta)).min(1.0);
+        driver.steer += (steer - driver.steer) * response;
+        driver.throttle += (throttle - driver.throttle) * response;
+
+        let grip = slipping.map_or(1.0, |s| s.grip());
+        let steering = grip * damage.map_or(1.0, |d| d.steering());
+        let acceleration = grip * damage.map_or(1.0, |d| d.acceleration());
+        a.0 += delta * 3.0 * steering * driver.steer;
+        v.0 += delta * 580.0 * acceleration * driver.throttle * Vec2::from_angle(a.0);
+
+        a.normalize();
+        t.rotation = a.to_quat();
+    }
+}
+
+/// Steer by comparing samples of the guidance field to either side of the
+/// car.
+///
+/// This can't plan ahead so it is only used if there is no racing line.
+fn whisker_controls(
+    guide: &guidance::GuidanceField,
+    pos: Vec2,
+    angle: f32,
+    gizmos: Option<&mut Gizmos>,
+) -> (f32, f32) {
+    let left_whisker = pos + (425.0 * Vec2::from_angle(angle + (PI / 12.)));
+    let left_pixel = guide.get(&left_whisker);
+    let right_whisker = pos + (425.0 * Vec2::from_angle(angle - (PI / 12.)));
+    let right_pixel = guide.get(&right_whisker);
+
+    let left_whisker2 = pos + (200.0 * Vec2::from_angle(angle + (PI / 6.)));
+    let left_pixel2 = guide.get(&left_whisker2);
+    let right_whisker2 = pos + (200.0 * Vec2::from_angle(angle - (PI / 6.)));
+    let right_pixel2 = guide.get(&right_whisker2);
+
+    let front_whisker = pos + (425.0 * Vec2::from_angle(angle));
+    let front_pixel = guide.get(&front_whisker);
+
+    if let Some(gizmos) = gizmos {
+        for v in [
+            left_whisker,
+            right_whisker,
+            left_whisker2,
+            right_whisker2,
+            front_whisker,
+        ] {
+            gizmos.circle_2d(v, 2.0, Color::BLUE);
+            gizmos.line_2d(pos, v, Color::BLUE);
+        }
+    }
+
+    let mut steer = 0.0;
+    if ((left_pixel - 10) > right_pixel) || ((left_pixel2 - 10) > right_pixel2) {
+        steer += 1.0;
+    }
+    if ((right_pixel - 10) > left_pixel) || ((right_pixel2 - 10) > left_pixel2) {
+        steer -= 1.0;
+    }
+
+    let throttle = if front_pixel > 50 { 1.0 } else { 0.0 };
+
+    (steer, throttle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{controls, detect_wrong_way, handle_lap_counter, objectmap, penalty};
+    use bevy::{
+        asset::AssetPlugin, ecs::system::CommandQueue, gizmos::GizmoPlugin,
+        render::render_resource::Shader, time::TimeUpdateStrategy, utils::HashMap,
+    };
+    use clap::Parser;
+    use std::time::Duration;
+
+    /// Most collisions between the AI cars that we put up with for every lap
+    /// they complete. The scramble for the first corner accounts for most
+    /// of them.
+    const MAX_COLLISIONS_PER_LAP: f32 = 6.0;
+
+    /// Give up if the AI hasn't finished a lap of level1 in this many frames.
+    const MAX_FRAMES: usize = 120 * 60;
+
+    /// Cars that touch again within this time (in seconds) are still in the
+    /// same collision.
+    const CONTACT_GAP: f32 = 0.5;
+
+    /// A headless app with (just enough of) the renderer to keep the debug
+    /// drawing happy.
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+            .init_asset::<Shader>()
+            .add_plugins(GizmoPlugin)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(
+                1.0 / 60.0,
+            )))
+            .insert_resource(Preferences::parse_from(["tdr2024"]));
+        app
+    }
+
+    #[derive(Resource, Default)]
+    struct Collisions {
+        last_contact: HashMap<(Entity, Entity), f32>,
+        count: usize,
+    }
+
+    /// Count the collisions between cars (rather than the frames in which
+    /// they are touching).
+    fn count_collisions(
+        mut events: EventReader<physics::CarCarCollision>,
+        cars: Query<(), With<Racer>>,
+        time: Res<Time>,
+        mut collisions: ResMut<Collisions>,
+    ) {
+        let now = time.elapsed_seconds();
+        let Collisions {
+            last_contact,
+            count,
+        } = &mut *collisions;
+        for e in events.read() {
+            if !cars.contains(e.a) || !cars.contains(e.b) {
+                continue;
+            }
+            let pair = (e.a.min(e.b), e.a.max(e.b));
+            match last_contact.insert(pair, now) {
+                Some(then) if now - then < CONTACT_GAP => (),
+                _ => *count += 1,
+            }
+        }
+    }
+
+    /// Race the whole field round level1 with every car driven by the AI.
+    #[test]
+    fn ai_race_is_mostly_clean() {
+        let map = tiled::Loader::new()
+            .load_tmx_map(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/assets/level1.tmx"
+            ))
+            .unwrap();
+
+        let mut app = app();
+        app.add_event::<physics::CarCarCollision>()
+            .add_event::<physics::CarSceneryCollision>()
+            .add_event::<respawn::Respawn>()
+            .add_event::<penalty::Infringement>()
+            .init_resource::<Collisions>()
+            .add_systems(
+                Update,
+                (
+                    sense_traffic,
+                    handle_ai_players,
+                    physics::apply_velocity,
+                    physics::apply_friction,
+                    physics::collision_detection,
+                    physics::fixed_collision_detection,
+                    detect_wrong_way,
+                    handle_lap_counter,
+                    count_collisions,
+                )
+                    .chain(),
+            );
+
+        let mut queue = CommandQueue::default();
+        objectmap::spawn_objects(
+            &map,
+            &mut Commands::new(&mut queue, &app.world),
+            &mut |_| default(),
+        );
+        queue.apply(&mut app.world);
+
+        // Hand the player's car to the AI too, seeding the drivers so the
+        // race is the same every time
+        let human = app
+            .world
+            .query_filtered::<Entity, With<Player>>()
+            .single(&app.world);
+        app.world
+            .entity_mut(human)
+            .remove::<(Player, controls::Controls)>();
+        let mut grid = app
+            .world
+            .query_filtered::<(Entity, &Transform, &physics::Angle), With<Racer>>()
+            .iter(&app.world)
+            .map(|(car, xform, angle)| (car, xform.translation.truncate(), angle.0))
+            .collect::<Vec<_>>();
+        grid.sort_by_key(|(car, ..)| *car);
+        assert!(grid.len() > 2, "level1 should have a field of cars");
+        fastrand::seed(1);
+        for (car, ..) in &grid {
+            app.world
+                .entity_mut(*car)
+                .insert(Driver::new(Difficulty::Normal));
+        }
+
+        let guide = guidance::GuidanceField::from_map(&map).unwrap();
+        let (_, start, heading) = grid[0];
+        let line = RacingLine::from_guidance(&guide, start, heading).unwrap();
+        app.insert_resource(guide).insert_resource(line);
+
+        let mut laps = app.world.query::<&Racer>();
+        for _ in 0..MAX_FRAMES {
+            app.update();
+            if laps.iter(&app.world).all(|r| r.lap_count >= 1) {
+                break;
+            }
+        }
+
+        let laps = laps.iter(&app.world).map(|r| r.lap_count).sum::<u32>();
+        let collisions = app.world.resource::<Collisions>().count;
+        assert_eq!(laps as usize, grid.len(), "every car should finish a lap");
+        assert!(
+            collisions as f32 / laps as f32 <= MAX_COLLISIONS_PER_LAP,
+            "{collisions} collisions in {laps} laps"
+        );
+    }
+
+    /// Put a car at `pos`, heading along the x axis.
+    fn spawn_car(app: &mut App, pos: Vec2, speed: f32) -> Entity {
+        let angle = physics::Angle(0.0);
+        app.world
+            .spawn((
+                Transform::from_translation(pos.extend(0.0)).with_rotation(angle.to_quat()),
+                angle,
+                physics::Velocity(Vec2::new(speed, 0.0)),
+                physics::CollisionBox(Polygon::from_vec(&Vec2::new(70.0, 130.0))),
+                Racer::default(),
+                Driver::new(Difficulty::Normal),
+            ))
+            .id()
+    }
+
+    /// Catch up with a slower car (that is a little to the left) and see
+    /// what both drivers make of it.
+    fn catch_up(app: &mut App, pos: Vec2) -> (&Driver, &Driver) {
+        let car = spawn_car(app, pos, 300.0);
+        let slower = spawn_car(app, pos + Vec2::new(250.0, 20.0), 100.0);
+        app.add_systems(Update, sense_traffic).update();
+        (
+            app.world.get::<Driver>(car).unwrap(),
+            app.world.get::<Driver>(slower).unwrap(),
+        )
+    }
+
+    #[test]
+    fn overtake_and_defend() {
+        let mut app = app();
+        let (driver, slower) = catch_up(&mut app, Vec2::ZERO);
+        assert_eq!(driver.traffic_offset, -OVERTAKE_OFFSET);
+        assert_eq!(driver.speed_limit, None);
+
+        // ... and the slower car moves over to block it
+        assert_eq!(slower.traffic_offset, -DEFEND_OFFSET);
+    }
+
+    #[test]
+    fn lift_off_if_there_is_no_room_to_pass() {
+        let map = tiled::Loader::new()
+            .load_tmx_map(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/assets/level1.tmx"
+            ))
+            .unwrap();
+        let mut app = app();
+        app.insert_resource(guidance::GuidanceField::from_map(&map).unwrap());
+
+        // There's no track (and so no room) anywhere off the edge of the map
+        let (driver, _) = catch_up(&mut app, Vec2::new(1.0e6, 0.0));
+        assert_eq!(driver.traffic_offset, 0.0);
+        assert_eq!(driver.speed_limit, Some(100.0));
+    }
+}
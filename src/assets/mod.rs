@@ -22,6 +22,10 @@ impl bevy::prelude::Plugin for Plugin {
         embedded_asset!(app, p, "speeddial.png");
         embedded_asset!(app, p, "speedneedle.png");
 
+        embedded_asset!(app, p, "sounds/click.wav");
+        embedded_asset!(app, p, "sounds/squeal.wav");
+        embedded_asset!(app, p, "sounds/thud.wav");
+
         embedded_asset!(app, p, "icons/exit_right.png");
         embedded_asset!(app, p, "icons/right.png");
         embedded_asset!(app, p, "icons/wrench.png");
@@ -0,0 +1,256 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+#![allow(clippy::type_complexity)]
+
+use bevy::{
+    audio::{AddAudioSource, AudioSink, AudioSinkPlayback, Volume},
+    prelude::*,
+};
+use std::sync::Arc;
+
+use crate::{
+    menu, physics,
+    synth::{EngineParams, EngineSound},
+    GameState, Player, Racer,
+};
+
+/// Distance (in world units) at which an engine is heard at half volume.
+const HALF_VOLUME_DISTANCE: f32 = 900.0;
+
+/// Speed of sound in world units per second. This is slower than it should
+/// be to make the doppler shift more noticeable.
+const SPEED_OF_SOUND: f32 = 4000.0;
+
+#[derive(Default)]
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_audio_source::<EngineSound>().add_systems(
+            Update,
+            (
+                spawn_engine_audio,
+                update_engine_audio.after(physics::simulate_engine),
+                spawn_car_audio,
+                update_car_audio,
+                play_impacts
+                    .after(physics::collision_detection)
+                    .after(physics::fixed_collision_detection),
+                play_menu_clicks.run_if(in_state(GameState::Menu)),
+            ),
+        );
+    }
+}
+
+/// The procedural engine sound of a car.
+///
+/// The sound is spawned as a child of the car so it is despawned with it
+/// when the level changes.
+#[derive(Component, Debug)]
+struct EngineAudio {
+    params: Arc<EngineParams>,
+    sink: Entity,
+}
+
+/// Looping sounds attached to a human player's car.
+#[derive(Component, Debug)]
+struct CarAudio {
+    squeal: Entity,
+}
+
+/// Volume setting (from the menu) converted to a gain.
+fn master_gain(volume: &menu::Volume) -> f32 {
+    volume.0 as f32 / 9.0
+}
+
+fn spawn_engine_audio(
+    mut commands: Commands,
+    cars: Query<Entity, (With<Racer>, Without<EngineAudio>)>,
+    mut sounds: ResMut<Assets<EngineSound>>,
+) {
+    for car in cars.iter() {
+        let sound = EngineSound::default();
+        let params = sound.params.clone();
+        let sink = commands
+            .spawn((
+                Name::new("EngineSound"),
+                AudioSourceBundle {
+                    source: sounds.add(sound),
+                    settings: PlaybackSettings::LOOP,
+                },
+            ))
+            .id();
+
+        commands
+            .entity(car)
+            .insert(EngineAudio { params, sink })
+            .add_child(sink);
+    }
+}
+
+/// Feed the engine model of every car into its synthesizer.
+///
+/// The camera acts as the listener. Engines get quieter with distance from
+/// the camera, are panned according to where they are on screen and are
+/// doppler shifted by their speed relative to the camera.
+fn update_engine_audio(
+    cars: Query<(
+        &physics::Engine,
+        &physics::Velocity,
+        &Transform,
+        &EngineAudio,
+    )>,
+    camera: Query<&Transform, With<Camera>>,
+    sinks: Query<&AudioSink>,
+    volume: Res<menu::Volume>,
+    state: Res<State<GameState>>,
+    time: Res<Time>,
+    mut last_listener: Local<Option<Vec2>>,
+) {
+    let Some(listener) = camera.iter().next() else {
+        return;
+    };
+    let gain = master_gain(&volume);
+    let playing = *state.get() == GameState::Game;
+
+    let listener_pos = listener.translation.truncate();
+    let delta = time.delta_seconds();
+    let listener_vel = match *last_listener {
+        Some(last) if delta > 0.0 => (listener_pos - last) / delta,
+        _ => Vec2::ZERO,
+    };
+    *last_listener = Some(listener_pos);
+
+    for (engine, v, xform, audio) in cars.iter() {
+        let offset = xform.translation.truncate() - listener_pos;
+        let distance = offset.length();
+        let towards_listener = -offset.normalize_or_zero();
+
+        let attenuation = 1.0 / (1.0 + (distance / HALF_VOLUME_DISTANCE).powi(2));
+        let doppler = (SPEED_OF_SOUND + listener_vel.dot(-towards_listener))
+            / (SPEED_OF_SOUND - v.0.dot(towards_listener));
+
+        // Pan relative to the camera (which rotates in jenny mode)
+        let local = listener.rotation.inverse() * offset.extend(0.0);
+        let pan = local.x / (local.length() + HALF_VOLUME_DISTANCE / 4.0);
+
+        let params = &audio.params;
+        params.set_rpm(engine.rpm);
+        params.set_load(engine.load);
+        params.set_gain(gain * attenuation);
+        params.set_pitch(doppler);
+        params.set_pan(pan);
+
+        if let Ok(sink) = sinks.get(audio.sink) {
+            if playing {
+                sink.play();
+            } else {
+                sink.pause();
+            }
+        }
+    }
+}
+
+fn spawn_car_audio(
+    mut commands: Commands,
+    cars: Query<Entity, (With<Player>, Without<CarAudio>)>,
+    asset_server: Res<AssetServer>,
+) {
+    for car in cars.iter() {
+        let squeal = commands
+            .spawn((
+                Name::new("SquealSound"),
+                AudioBundle {
+                    source: asset_server.load("embedded://tdr2024/assets/sounds/squeal.wav"),
+                    settings: PlaybackSettings::LOOP.with_volume(Volume::new_relative(0.0)),
+                },
+            ))
+            .id();
+
+        commands
+            .entity(car)
+            .insert(CarAudio { squeal })
+            .add_child(squeal);
+    }
+}
+
+/// The tyres squeal when the velocity of the car is no longer lined up with
+/// the way it is pointing.
+fn update_car_audio(
+    cars: Query<(&physics::Velocity, &physics::Angle, &CarAudio)>,
+    sinks: Query<&AudioSink>,
+    volume: Res<menu::Volume>,
+    state: Res<State<GameState>>,
+) {
+    let gain = master_gain(&volume);
+    let playing = *state.get() == GameState::Game;
+
+    for (v, a, audio) in cars.iter() {
+        let slip = v.0.dot(Vec2::from_angle(a.0).perp()).abs();
+
+        if let Ok(sink) = sinks.get(audio.squeal) {
+            sink.set_volume(gain * ((slip - 150.0) / 400.0).clamp(0.0, 0.6));
+            if playing {
+                sink.play();
+            } else {
+                sink.pause();
+            }
+        }
+    }
+}
+
+/// Play a thud for every collision, scaled by the strength of the impact
+/// and the distance from the camera.
+fn play_impacts(
+    mut commands: Commands,
+    mut car_car: EventReader<physics::CarCarCollision>,
+    mut car_scenery: EventReader<physics::CarSceneryCollision>,
+    camera: Query<&Transform, With<Camera>>,
+    asset_server: Res<AssetServer>,
+    volume: Res<menu::Volume>,
+) {
+    let listener = camera
+        .iter()
+        .next()
+        .map(|xform| xform.translation.truncate())
+        .unwrap_or_default();
+
+    let impacts = car_car
+        .read()
+        // A light object bounces off a car without giving it much of a jolt
+        // so it is the heavier of the two that sets the volume
+        .map(|e| (e.point, e.impulse_a.min(e.impulse_b)))
+        .chain(car_scenery.read().map(|e| (e.point, e.impulse)));
+    for (point, impulse) in impacts {
+        if impulse < 50.0 {
+            continue;
+        }
+
+        let distance = point.distance(listener);
+        let attenuation = 1.0 / (1.0 + (distance / HALF_VOLUME_DISTANCE).powi(2));
+        commands.spawn(AudioBundle {
+            source: asset_server.load("embedded://tdr2024/assets/sounds/thud.wav"),
+            settings: PlaybackSettings::DESPAWN.with_volume(Volume::new_relative(
+                master_gain(&volume) * attenuation * (impulse / 800.0).min(1.0),
+            )),
+        });
+    }
+}
+
+fn play_menu_clicks(
+    mut commands: Commands,
+    interactions: Query<&Interaction, (Changed<Interaction>, With<Button>)>,
+    asset_server: Res<AssetServer>,
+    volume: Res<menu::Volume>,
+) {
+    for interaction in interactions.iter() {
+        if *interaction == Interaction::Pressed {
+            commands.spawn(AudioBundle {
+                source: asset_server.load("embedded://tdr2024/assets/sounds/click.wav"),
+                settings: PlaybackSettings::DESPAWN
+                    .with_volume(Volume::new_relative(master_gain(&volume))),
+            });
+        }
+    }
+}
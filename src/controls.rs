@@ -0,0 +1,447 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+#![allow(clippy::type_complexity)]
+
+use bevy::{
+    input::gamepad::{AxisSettings, GamepadSettings},
+    log,
+    prelude::*,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Player, Preferences, Racer};
+
+#[derive(Default)]
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Bindings>()
+            .add_systems(Startup, apply_dead_zones)
+            .add_systems(
+                Update,
+                (assign_gamepads, read_controls.after(assign_gamepads)),
+            );
+    }
+}
+
+/// The driving inputs for a single human controlled car.
+///
+/// Both values are analog. `steer` runs from -1.0 (full right lock) to 1.0
+/// (full left lock) and `throttle` runs from 0.0 to 1.0. Digital inputs,
+/// such as the keyboard, simply drive the values to their limits.
+///
+/// `reset` is only set for the frame in which the reset button is pressed.
+#[derive(Component, Debug, Default)]
+pub struct Controls {
+    pub steer: f32,
+    pub throttle: f32,
+    pub reset: bool,
+}
+
+/// Everything a player can do with a key or a (digital) gamepad button.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Action {
+    SteerLeft,
+    SteerRight,
+    Accelerate,
+    Reset,
+    Menu,
+}
+
+impl Action {
+    pub const ALL: [Action; 5] = [
+        Action::SteerLeft,
+        Action::SteerRight,
+        Action::Accelerate,
+        Action::Reset,
+        Action::Menu,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Action::SteerLeft => "Steer left",
+            Action::SteerRight => "Steer right",
+            Action::Accelerate => "Accelerate",
+            Action::Reset => "Back to checkpoint",
+            Action::Menu => "Menu",
+        }
+    }
+}
+
+/// A single physical input that can trigger an [Action].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    Button(GamepadButtonType),
+}
+
+impl Binding {
+    fn same_kind(&self, other: &Binding) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+impl std::fmt::Display for Binding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Binding::Key(key) => write!(f, "{key:?}"),
+            Binding::Button(button) => write!(f, "Pad {button:?}"),
+        }
+    }
+}
+
+/// The action-to-binding map.
+///
+/// An action can have any number of bindings. The analog controls (left stick
+/// and right trigger) are not included; they are always active.
+#[derive(Resource, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bindings(Vec<(Action, Binding)>);
+
+impl Default for Bindings {
+    fn default() -> Self {
+        use Binding::*;
+        Self(vec![
+            (Action::SteerLeft, Key(KeyCode::Z)),
+            (Action::SteerLeft, Button(GamepadButtonType::DPadLeft)),
+            (Action::SteerRight, Key(KeyCode::X)),
+            (Action::SteerRight, Button(GamepadButtonType::DPadRight)),
+            (Action::Accelerate, Key(KeyCode::ShiftLeft)),
+            (Action::Accelerate, Key(KeyCode::ShiftRight)),
+            (Action::Accelerate, Button(GamepadButtonType::South)),
+            (Action::Reset, Key(KeyCode::R)),
+            (Action::Reset, Button(GamepadButtonType::North)),
+            (Action::Menu, Key(KeyCode::Escape)),
+            (Action::Menu, Button(GamepadButtonType::Start)),
+        ])
+    }
+}
+
+impl Bindings {
+    pub fn get(&self, action: Action) -> impl Iterator<Item = &Binding> + '_ {
+        self.0
+            .iter()
+            .filter(move |(a, _)| *a == action)
+            .map(|(_, binding)| binding)
+    }
+
+    /// Test whether an action is active on the keyboard and/or a gamepad.
+    pub fn pressed(
+        &self,
+        action: Action,
+        keyboard: Option<&Input<KeyCode>>,
+        gamepad: Option<(Gamepad, &Input<GamepadButton>)>,
+    ) -> bool {
+        self.test(action, keyboard, gamepad, false)
+    }
+
+    /// Test whether an action has been triggered during this frame.
+    pub fn just_pressed(
+        &self,
+        action: Action,
+        keyboard: Option<&Input<KeyCode>>,
+        gamepad: Option<(Gamepad, &Input<GamepadButton>)>,
+    ) -> bool {
+        self.test(action, keyboard, gamepad, true)
+    }
+
+    fn test(
+        &self,
+        action: Action,
+        keyboard: Option<&Input<KeyCode>>,
+        gamepad: Option<(Gamepad, &Input<GamepadButton>)>,
+        just: bool,
+    ) -> bool {
+        self.get(action)
+            .any(|binding| match (binding, keyboard, gamepad) {
+                (Binding::Key(key), Some(keyboard), _) if just => keyboard.just_pressed(*key),
+                (Binding::Key(key), Some(keyboard), _) => keyboard.pressed(*key),
+                (Binding::Button(button), _, Some((pad, buttons))) => {
+                    let button = GamepadButton::new(pad, *button);
+                    if just {
+                        buttons.just_pressed(button)
+                    } else {
+                        buttons.pressed(button)
+                    }
+                }
+                _ => false,
+            })
+    }
+
+    /// Give any action that has no bindings at all its default bindings
+    /// (e.g. for an action added since the settings were saved). Inputs that
+    /// are already in use are left alone.
+    pub fn add_missing(&mut self) {
+        let missing = Action::ALL
+            .into_iter()
+            .filter(|action| self.get(*action).next().is_none())
+            .collect::<Vec<_>>();
+        let defaults = Self::default()
+            .0
+            .into_iter()
+            .filter(|(action, binding)| {
+                missing.contains(action) && !self.0.iter().any(|(_, b)| b == binding)
+            })
+            .collect::<Vec<_>>();
+        self.0.extend(defaults);
+    }
+
+    /// Bind an input to an action.
+    ///
+    /// The new binding replaces any existing bindings of the same kind (key or
+    /// gamepad button) for that action. If the input was already bound to a
+    /// different action then it is taken away from that action, which is
+    /// reported to the caller so they can warn the user about the conflict.
+    pub fn rebind(&mut self, action: Action, binding: Binding) -> Option<Action> {
+        let conflict = self
+            .0
+            .iter()
+            .find(|(a, b)| *a != action && *b == binding)
+            .map(|(a, _)| *a);
+
+        self.0
+            .retain(|(a, b)| *b != binding && !(*a == action && b.same_kind(&binding)));
+        self.0.push((action, binding));
+
+        conflict
+    }
+
+    /// Find every input that is bound to more than one action.
+    pub fn conflicts(&self) -> Vec<(Binding, Action, Action)> {
+        self.0
+            .iter()
+            .enumerate()
+            .flat_map(|(n, (a, binding))| {
+                self.0[n + 1..]
+                    .iter()
+                    .filter(move |(b, other)| other == binding && a != b)
+                    .map(move |(b, _)| (*binding, *a, *b))
+            })
+            .collect()
+    }
+}
+
+/// Apply the dead zone from the preferences to every gamepad axis.
+///
+/// The dead zone is applied symmetrically to the sticks and is also used as
+/// the low threshold for analog buttons (i.e. the triggers).
+fn apply_dead_zones(mut settings: ResMut<GamepadSettings>, prefs: Res<Preferences>) {
+    let dz = prefs.dead_zone.clamp(0.0, 0.9);
+    match AxisSettings::new(-1.0, -dz, dz, 1.0, 0.01) {
+        Ok(axis) => settings.default_axis_settings = axis,
+        Err(e) => log::error!("Cannot apply dead zone: {e:?}"),
+    }
+    settings.default_button_axis_settings.low = dz;
+}
+
+/// Map each connected gamepad to a human player.
+///
+/// The first gamepad shares seat 0 (the red car) with the keyboard. Any
+/// additional gamepads take over the AI cars, one car per gamepad, and hand
+/// them back to the AI if the gamepad is disconnected.
+fn assign_gamepads(
+    mut commands: Commands,
+    gamepads: Res<Gamepads>,
+    cars: Query<(Entity, Option<&Player>), With<Racer>>,
+) {
+    let mut pads = gamepads.iter().collect::<Vec<_>>();
+    pads.sort_by_key(|pad| pad.id);
+
+    let mut ai_cars = cars
+        .iter()
+        .filter(|(_, player)| !matches!(player, Some(Player(0))))
+        .collect::<Vec<_>>();
+    ai_cars.sort_by_key(|(entity, _)| *entity);
+
+    for (n, (entity, player)) in ai_cars.into_iter().enumerate() {
+        let seat = n + 1;
+        match (seat < pads.len(), player) {
+            (true, None) => {
+                log::info!("Gamepad {} takes control of {entity:?}", pads[seat].id);
+                commands
+                    .entity(entity)
+                    .insert((Player(seat), Controls::default()));
+            }
+            (false, Some(_)) => {
+                log::info!("Returning {entity:?} to the AI");
+                commands.entity(entity).remove::<(Player, Controls)>();
+            }
+            _ => (),
+        }
+    }
+}
+
+pub fn read_controls(
+    mut query: Query<(&Player, &mut Controls)>,
+    bindings: Res<Bindings>,
+    keyboard: Res<Input<KeyCode>>,
+    gamepads: Res<Gamepads>,
+    buttons: Res<Input<GamepadButton>>,
+    button_axes: Res<Axis<GamepadButton>>,
+    axes: Res<Axis<GamepadAxis>>,
+) {
+    let mut pads = gamepads.iter().collect::<Vec<_>>();
+    pads.sort_by_key(|pad| pad.id);
+
+    for (Player(seat), mut controls) in query.iter_mut() {
+        let keyboard = (*seat == 0).then_some(keyboard.as_ref());
+        let gamepad = pads.get(*seat).map(|&pad| (pad, buttons.as_ref()));
+        let pressed = |action| bindings.pressed(action, keyboard, gamepad);
+
+        let mut steer = 0.0;
+        let mut throttle = 0.0;
+
+        if pressed(Action::SteerLeft) {
+            steer += 1.0;
+        }
+        if pressed(Action::SteerRight) {
+            steer -= 1.0;
+        }
+        if pressed(Action::Accelerate) {
+            throttle = 1.0;
+        }
+
+        if let Some((pad, _)) = gamepad {
+            steer -= axes
+                .get(GamepadAxis::new(pad, GamepadAxisType::LeftStickX))
+                .unwrap_or(0.0);
+
+            let trigger = button_axes
+                .get(GamepadButton::new(pad, GamepadButtonType::RightTrigger2))
+                .unwrap_or(0.0);
+            throttle = f32::max(throttle, trigger);
+        }
+
+        controls.steer = steer.clamp(-1.0, 1.0);
+        controls.throttle = throttle.clamp(0.0, 1.0);
+        controls.reset = bindings.just_pressed(Action::Reset, keyboard, gamepad);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::input::{
+        gamepad::{
+            GamepadAxisChangedEvent, GamepadButtonChangedEvent, GamepadConnection,
+            GamepadConnectionEvent, GamepadEvent, GamepadInfo,
+        },
+        InputPlugin,
+    };
+    use clap::Parser;
+
+    /// A headless app with a human player and two AI cars.
+    fn app() -> (App, Entity, [Entity; 2]) {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, InputPlugin, Plugin))
+            .insert_resource(Preferences::parse_from(["tdr2024", "--dead-zone", "0.2"]));
+
+        let human = app
+            .world
+            .spawn((Racer::default(), Player(0), Controls::default()))
+            .id();
+        let ai = [
+            app.world.spawn(Racer::default()).id(),
+            app.world.spawn(Racer::default()).id(),
+        ];
+        app.update();
+        (app, human, ai)
+    }
+
+    fn connect(app: &mut App, id: usize, connected: bool) {
+        let connection = if connected {
+            GamepadConnection::Connected(GamepadInfo {
+                name: format!("Pad {id}"),
+            })
+        } else {
+            GamepadConnection::Disconnected
+        };
+        app.world
+            .send_event(GamepadEvent::Connection(GamepadConnectionEvent::new(
+                Gamepad::new(id),
+                connection,
+            )));
+    }
+
+    /// Move a stick, filtering the raw value the way the gamepad backend
+    /// does.
+    fn move_axis(app: &mut App, id: usize, axis_type: GamepadAxisType, raw: f32) {
+        let axis = GamepadAxis::new(Gamepad::new(id), axis_type);
+        let settings = app
+            .world
+            .resource::<GamepadSettings>()
+            .get_axis_settings(axis);
+        let old = app.world.resource::<Axis<GamepadAxis>>().get(axis);
+        if let Some(value) = settings.filter(raw, old) {
+            app.world
+                .send_event(GamepadEvent::Axis(GamepadAxisChangedEvent::new(
+                    Gamepad::new(id),
+                    axis_type,
+                    value,
+                )));
+        }
+    }
+
+    fn press(app: &mut App, id: usize, button_type: GamepadButtonType) {
+        app.world
+            .send_event(GamepadEvent::Button(GamepadButtonChangedEvent::new(
+                Gamepad::new(id),
+                button_type,
+                1.0,
+            )));
+    }
+
+    fn controls(app: &App, car: Entity) -> Option<(f32, f32)> {
+        app.world
+            .get::<Controls>(car)
+            .map(|c| (c.steer, c.throttle))
+    }
+
+    #[test]
+    fn second_gamepad_takes_over_an_ai_car() {
+        let (mut app, human, ai) = app();
+
+        connect(&mut app, 0, true);
+        app.update();
+        assert!(app.world.get::<Player>(ai[0]).is_none());
+
+        connect(&mut app, 1, true);
+        app.update();
+        assert_eq!(app.world.get::<Player>(ai[0]).map(|p| p.0), Some(1));
+        assert!(app.world.get::<Player>(ai[1]).is_none());
+        assert_eq!(app.world.get::<Player>(human).map(|p| p.0), Some(0));
+
+        connect(&mut app, 1, false);
+        app.update();
+        assert!(app.world.get::<Player>(ai[0]).is_none());
+        assert!(app.world.get::<Controls>(ai[0]).is_none());
+    }
+
+    #[test]
+    fn gamepad_drives_its_own_car() {
+        let (mut app, human, ai) = app();
+        connect(&mut app, 0, true);
+        connect(&mut app, 1, true);
+        app.update();
+
+        // A small movement is lost in the dead zone...
+        move_axis(&mut app, 1, GamepadAxisType::LeftStickX, 0.15);
+        app.update();
+        app.update();
+        assert_eq!(controls(&app, ai[0]), Some((0.0, 0.0)));
+
+        // ... but a big one gets through (pushing the stick right steers
+        // right, which is negative)
+        move_axis(&mut app, 1, GamepadAxisType::LeftStickX, 0.8);
+        press(&mut app, 1, GamepadButtonType::South);
+        app.update();
+        let (steer, throttle) = controls(&app, ai[0]).unwrap();
+        assert!(steer < -0.7, "steer {steer}");
+        assert_eq!(throttle, 1.0);
+
+        // The other players aren't affected
+        assert_eq!(controls(&app, human), Some((0.0, 0.0)));
+    }
+}
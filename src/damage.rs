@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+use bevy::prelude::*;
+
+use crate::physics;
+
+/// Impacts weaker than this (a change of velocity, in world units per
+/// second) do not cause any damage.
+const DAMAGE_THRESHOLD: f32 = 100.0;
+
+/// Change of velocity (above the threshold) that wrecks a car in a single
+/// impact against fully hard scenery.
+const WRECKING_IMPULSE: f32 = 4000.0;
+
+/// Hardness of another car. Cars crumple a bit so car-on-car impacts are
+/// less damaging than hitting a wall.
+const CAR_HARDNESS: f32 = 0.7;
+
+/// Top speed of an undamaged car (the speed at which the thrust of the
+/// fastest car and friction balance).
+const TOP_SPEED: f32 = 580.0 / 1.2;
+
+#[derive(Default)]
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            (
+                apply_collision_damage
+                    .after(physics::collision_detection)
+                    .after(physics::fixed_collision_detection),
+                limit_speed.after(physics::apply_friction),
+            ),
+        );
+    }
+}
+
+/// Accumulated damage, from 0.0 (as new) to 1.0 (wrecked).
+///
+/// Cars are only given this component if the level has damage enabled.
+#[derive(Component, Debug, Default)]
+pub struct Damage(pub f32);
+
+impl Damage {
+    /// Fraction of the full thrust that the car can still produce.
+    pub fn acceleration(&self) -> f32 {
+        1.0 - 0.5 * self.0
+    }
+
+    /// Fraction of the full steering rate that the car can still manage.
+    pub fn steering(&self) -> f32 {
+        1.0 - 0.4 * self.0
+    }
+
+    /// The fastest the car can go.
+    pub fn top_speed(&self) -> f32 {
+        TOP_SPEED * (1.0 - 0.3 * self.0)
+    }
+
+    fn add_impact(&mut self, impulse: f32, hardness: f32) {
+        let excess = (impulse - DAMAGE_THRESHOLD).max(0.0);
+        self.0 = (self.0 + hardness * excess / WRECKING_IMPULSE).min(1.0);
+    }
+}
+
+/// How much an object hurts when you drive into it, from 0.0 (harmless) to
+/// 1.0 (a brick wall).
+#[derive(Component, Debug)]
+pub struct Hardness(pub f32);
+
+impl Hardness {
+    /// Pick a sensible hardness based on the sprite.
+    ///
+    /// Designers can override this using a `hardness` property on either the
+    /// object or the tile.
+    pub fn from_image(img_src: &str) -> Self {
+        Self(if img_src.contains("tires") {
+            0.2
+        } else if img_src.contains("barrier") {
+            0.4
+        } else if img_src.contains("cone") {
+            0.1
+        } else {
+            1.0
+        })
+    }
+}
+
+fn apply_collision_damage(
+    mut car_car: EventReader<physics::CarCarCollision>,
+    mut car_scenery: EventReader<physics::CarSceneryCollision>,
+    mut cars: Query<&mut Damage>,
+    scenery: Query<&Hardness>,
+) {
+    for e in car_car.read() {
+        for (car, other, impulse) in [(e.a, e.b, e.impulse_a), (e.b, e.a, e.impulse_b)] {
+            // Pushable scenery has its own hardness
+            let hardness = scenery.get(other).map(|h| h.0).unwrap_or(CAR_HARDNESS);
+            if let Ok(mut damage) = cars.get_mut(car) {
+                damage.add_impact(impulse, hardness);
+            }
+        }
+    }
+
+    for e in car_scenery.read() {
+        let hardness = scenery.get(e.scenery).map(|h| h.0).unwrap_or(1.0);
+        if let Ok(mut damage) = cars.get_mut(e.car) {
+            damage.add_impact(e.impulse, hardness);
+        }
+    }
+}
+
+fn limit_speed(mut cars: Query<(&mut physics::Velocity, &Damage)>) {
+    for (mut v, damage) in cars.iter_mut() {
+        v.0 = v.0.clamp_length_max(damage.top_speed());
+    }
+}
@@ -3,22 +3,83 @@
 
 #![allow(clippy::type_complexity)]
 
-use super::{physics, Player, Racer};
+use super::{damage, menu::DigitalSpeedo, physics, Player, Racer, RACE_LAPS};
 use bevy::prelude::*;
+use std::{f32::consts::PI, fmt::Write};
 
-#[derive(Component, Debug)]
-struct LapMeter;
+/// One line of the race HUD.
+#[derive(Component, Clone, Copy, Debug)]
+enum RaceInfo {
+    Position,
+    Lap,
+    LapTime,
+    LastLap,
+    BestLap,
+    Delta,
+    Penalty,
+}
+
+const TEXT_COLOR: Color = Color::rgb(0.9, 0.9, 0.9);
+const AHEAD_COLOR: Color = Color::rgb(0.2, 0.9, 0.2);
+const BEHIND_COLOR: Color = Color::rgb(0.9, 0.2, 0.2);
 
 #[derive(Component, Debug)]
 struct Speedometer;
 
+#[derive(Component, Debug)]
+struct DigitalSpeed;
+
+/// One of the lights of the rev counter, numbered from the left.
+#[derive(Component, Debug)]
+struct RevLight(usize);
+
+/// Speed, in km/h, for each world unit per second. The cars are toys
+/// really; this is picked so that flat out (about 470 units per second) is a
+/// little over 110 km/h.
+const KMH_PER_UNIT: f32 = 0.24;
+
+/// The speedometer is marked from 0 to 120 km/h over 252 degrees, starting
+/// from where the needle points in the image.
+const DIAL_MAX_KMH: f32 = 120.0;
+const DIAL_SWEEP: f32 = 252.0 * PI / 180.0;
+
+/// How quickly the needle and rev counter catch up with the car (per
+/// second). The needle has some weight to it so it doesn't flick about
+/// when the car hits something.
+const NEEDLE_RESPONSE: f32 = 8.0;
+
+/// The rev counter runs from 0 to `TACHO_MAX_RPM` over `REV_LIGHTS` lights.
+/// The lights turn amber, then red, as the engine nears the point where the
+/// gearbox changes up.
+const TACHO_MAX_RPM: f32 = 7000.0;
+const REV_LIGHTS: usize = 14;
+const AMBER_RPM: f32 = 5000.0;
+const RED_RPM: f32 = 6000.0;
+
+#[derive(Component, Debug)]
+struct DamageMeter;
+
+#[derive(Component, Debug)]
+struct Results;
+
+#[derive(Component, Debug)]
+struct WrongWay;
+
 #[derive(Default)]
 pub struct Plugin;
 
 impl bevy::app::Plugin for Plugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, spawn_dashboard)
-            .add_systems(Update, (update_lap_counter, update_speedo));
+        app.add_systems(Startup, spawn_dashboard).add_systems(
+            Update,
+            (
+                update_race_info,
+                update_speedo,
+                update_damage_meter,
+                update_results,
+                update_wrong_way,
+            ),
+        );
     }
 }
 
@@ -93,6 +154,52 @@ fn spawn_dashboard(mut commands: Commands, asset_server: Res<AssetServer>) {
                         ),
                     ));
                 });
+        })
+        .with_children(|parent| {
+            // The rev counter, and the digital speed readout, sit just below
+            // the dial
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        width: Val::Percent(100.0),
+                        position_type: PositionType::Absolute,
+                        top: Val::VMax(100.0 * 264.0 / 1920.0),
+                        flex_direction: FlexDirection::Column,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    ..default()
+                })
+                .with_children(|parent| {
+                    parent.spawn(NodeBundle::default()).with_children(|parent| {
+                        for n in 0..REV_LIGHTS {
+                            parent.spawn((
+                                RevLight(n),
+                                NodeBundle {
+                                    style: Style {
+                                        width: Val::VMax(0.6),
+                                        height: Val::VMax(1.2),
+                                        margin: UiRect::all(Val::VMax(0.1)),
+                                        ..default()
+                                    },
+                                    background_color: rev_light_color(n, false).into(),
+                                    ..default()
+                                },
+                            ));
+                        }
+                    });
+                    parent.spawn((
+                        DigitalSpeed,
+                        TextBundle::from_section(
+                            "",
+                            TextStyle {
+                                font_size: 32.0,
+                                color: TEXT_COLOR,
+                                ..default()
+                            },
+                        ),
+                    ));
+                });
         });
     commands
         .spawn((
@@ -121,10 +228,42 @@ fn spawn_dashboard(mut commands: Commands, asset_server: Res<AssetServer>) {
                     ..default()
                 })
                 .with_children(|parent| {
+                    parent
+                        .spawn(NodeBundle {
+                            style: Style {
+                                flex_direction: FlexDirection::Column,
+                                margin: UiRect::all(Val::Px(50.0)),
+                                ..default()
+                            },
+                            ..default()
+                        })
+                        .with_children(|parent| {
+                            for (info, font_size) in [
+                                (RaceInfo::Position, 48.0),
+                                (RaceInfo::Lap, 32.0),
+                                (RaceInfo::LapTime, 24.0),
+                                (RaceInfo::LastLap, 24.0),
+                                (RaceInfo::BestLap, 24.0),
+                                (RaceInfo::Delta, 24.0),
+                                (RaceInfo::Penalty, 32.0),
+                            ] {
+                                parent.spawn((
+                                    info,
+                                    TextBundle::from_section(
+                                        "",
+                                        TextStyle {
+                                            font_size,
+                                            color: TEXT_COLOR,
+                                            ..default()
+                                        },
+                                    ),
+                                ));
+                            }
+                        });
                     parent.spawn((
-                        LapMeter,
+                        DamageMeter,
                         TextBundle::from_section(
-                            "Lap 0",
+                            "",
                             TextStyle {
                                 font_size: 32.0,
                                 color: Color::rgb(0.9, 0.9, 0.9),
@@ -138,40 +277,298 @@ fn spawn_dashboard(mut commands: Commands, asset_server: Res<AssetServer>) {
                     ));
                 });
         });
+    commands.spawn((
+        Name::new("Results"),
+        Results,
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 28.0,
+                color: Color::rgb(0.9, 0.9, 0.9),
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            left: Val::Percent(30.0),
+            top: Val::Percent(15.0),
+            ..default()
+        })
+        .with_background_color(Color::rgba(0.0, 0.0, 0.0, 0.6)),
+    ));
+    commands
+        .spawn((
+            Name::new("WrongWay"),
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    position_type: PositionType::Absolute,
+                    top: Val::Percent(20.0),
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                },
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                WrongWay,
+                TextBundle {
+                    visibility: Visibility::Hidden,
+                    ..TextBundle::from_section(
+                        "WRONG WAY",
+                        TextStyle {
+                            font_size: 64.0,
+                            color: Color::rgb(0.9, 0.1, 0.1),
+                            ..default()
+                        },
+                    )
+                },
+            ));
+        });
 }
 
-fn update_lap_counter(
-    player: Query<(&Racer, With<Player>)>,
-    mut label: Query<(&mut Text, With<LapMeter>)>,
+/// Show the lead player's place in the race, their lap times and any
+/// penalty they are serving.
+fn update_race_info(
+    player: Query<(&Racer, &Player)>,
+    racers: Query<(), With<Racer>>,
+    mut labels: Query<(&mut Text, &RaceInfo)>,
+    time: Res<Time>,
+    mut scratch: Local<String>,
 ) {
-    let Some((car, _)) = player.iter().next() else {
+    let Some((car, _)) = player.iter().find(|(_, p)| p.is_lead()) else {
         return;
     };
-    let (mut txt, _) = label.single_mut();
+    let now = time.elapsed_seconds();
 
-    // TODO: This is rather cumbersome but I'd rather not work the memory
-    //       allocator every frame...
-    let label = &txt.sections[0].value;
-    let len = label.len();
-    let value = label[len - 1..len].parse::<u32>();
-    if let Ok(value) = value {
-        if value != car.lap_count {
-            txt.sections[0].value = format!("Lap {}", car.lap_count);
+    for (mut txt, info) in labels.iter_mut() {
+        // Format into a scratch buffer and only touch the label if it has
+        // changed (so we don't work the memory allocator, or redraw the
+        // text, every frame)
+        scratch.clear();
+        let mut color = TEXT_COLOR;
+        match info {
+            RaceInfo::Position => write!(scratch, "P{}/{}", car.position, racers.iter().count()),
+            RaceInfo::Lap if car.lap_count >= RACE_LAPS => write!(scratch, "Finished"),
+            RaceInfo::Lap => write!(scratch, "Lap {}/{RACE_LAPS}", car.lap_count + 1),
+            RaceInfo::LapTime => write_time(&mut scratch, "Time", car.times.current(now)),
+            RaceInfo::LastLap => write_time(&mut scratch, "Last", car.times.last),
+            RaceInfo::BestLap => write_time(&mut scratch, "Best", car.times.best),
+            RaceInfo::Delta => match car.times.delta {
+                Some(delta) => {
+                    color = if delta <= 0.0 {
+                        AHEAD_COLOR
+                    } else {
+                        BEHIND_COLOR
+                    };
+                    write!(scratch, "{delta:+.2}")
+                }
+                None => Ok(()),
+            },
+            RaceInfo::Penalty if car.penalty > 0.0 => {
+                color = BEHIND_COLOR;
+                write!(scratch, "Penalty {:.1}s", car.penalty)
+            }
+            RaceInfo::Penalty => Ok(()),
+        }
+        .unwrap();
+
+        let section = &mut txt.bypass_change_detection().sections[0];
+        if section.value != *scratch || section.style.color != color {
+            section.value.clone_from(&scratch);
+            section.style.color = color;
+            txt.set_changed();
         }
-    } else {
-        error!("Invalid lap count: {label}");
-        txt.sections[0].value = format!("Lap {}", car.lap_count);
     }
 }
 
+/// Write a lap time as minutes, seconds and hundredths.
+fn write_time(out: &mut String, label: &str, time: Option<f32>) -> std::fmt::Result {
+    match time {
+        Some(time) => {
+            let hundredths = (time * 100.0).round() as u32;
+            write!(
+                out,
+                "{label} {}:{:02}.{:02}",
+                hundredths / 6000,
+                hundredths / 100 % 60,
+                hundredths % 100
+            )
+        }
+        None => write!(out, "{label} -:--.--"),
+    }
+}
+
+/// What the speedometer and rev counter are showing at the moment.
+#[derive(Default)]
+struct Gauges {
+    speed: f32,
+    rpm: f32,
+    /// The value in the digital readout
+    kmh: Option<u32>,
+}
+
+/// Move the speedometer needle, rev counter and digital readout to match
+/// the lead player's car.
 fn update_speedo(
-    player: Query<(&physics::Velocity, With<Player>)>,
-    mut speedo: Query<(&mut Transform, With<Speedometer>)>,
+    player: Query<(&physics::Velocity, Option<&physics::Engine>, &Player)>,
+    mut speedo: Query<&mut Transform, With<Speedometer>>,
+    mut lights: Query<(&RevLight, &mut BackgroundColor)>,
+    mut digital: Query<(&mut Text, &mut Visibility), With<DigitalSpeed>>,
+    show_digital: Res<DigitalSpeedo>,
+    time: Res<Time>,
+    mut shown: Local<Gauges>,
+) {
+    let Some((vp, engine, _)) = player.iter().find(|(.., p)| p.is_lead()) else {
+        return;
+    };
+
+    // Damp the needles so they swing rather than jump
+    let response = (time.delta_seconds() * NEEDLE_RESPONSE).min(1.0);
+    shown.speed += (vp.0.length() * KMH_PER_UNIT - shown.speed) * response;
+    shown.rpm += (engine.map_or(0.0, |e| e.rpm) - shown.rpm) * response;
+
+    let mut needle = speedo.single_mut();
+    needle.rotation =
+        Quat::from_rotation_z(DIAL_SWEEP * (shown.speed / DIAL_MAX_KMH).clamp(0.0, 1.0));
+
+    let lit = ((shown.rpm / TACHO_MAX_RPM) * REV_LIGHTS as f32).round() as usize;
+    for (RevLight(n), mut color) in lights.iter_mut() {
+        let new_color = rev_light_color(*n, *n < lit);
+        if color.0 != new_color {
+            color.0 = new_color;
+        }
+    }
+
+    let (mut txt, mut visibility) = digital.single_mut();
+    let new_visibility = if show_digital.0 {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+    if *visibility != new_visibility {
+        *visibility = new_visibility;
+    }
+
+    // Only reformat the label when the (rounded) value changes
+    let kmh = shown.speed.round() as u32;
+    if shown.kmh != Some(kmh) {
+        shown.kmh = Some(kmh);
+        txt.sections[0].value = format!("{kmh} km/h");
+    }
+}
+
+fn rev_light_color(n: usize, lit: bool) -> Color {
+    let rpm = (n + 1) as f32 * TACHO_MAX_RPM / REV_LIGHTS as f32;
+    let color = if rpm > RED_RPM {
+        Color::rgb(0.9, 0.1, 0.1)
+    } else if rpm > AMBER_RPM {
+        Color::rgb(0.9, 0.6, 0.1)
+    } else {
+        Color::rgb(0.1, 0.8, 0.2)
+    };
+    if lit {
+        color
+    } else {
+        color.with_a(0.2)
+    }
+}
+
+/// Show how battered the lead player's car is.
+///
+/// The meter is left blank on levels where damage is turned off.
+fn update_damage_meter(
+    player: Query<(Option<&damage::Damage>, &Player)>,
+    mut label: Query<(&mut Text, With<DamageMeter>)>,
+    mut last: Local<Option<u32>>,
 ) {
-    let Some((vp, _)) = player.iter().next() else {
+    let Some((damage, _)) = player.iter().find(|(_, p)| p.is_lead()) else {
         return;
     };
-    let (mut needle, _) = speedo.single_mut();
+    let (mut txt, _) = label.single_mut();
+
+    // Only reformat the label when the (rounded) value changes
+    let percent = damage.map(|d| (d.0 * 100.0).round() as u32);
+    if *last != percent || txt.is_added() {
+        *last = percent;
+        txt.sections[0].value = match percent {
+            Some(percent) => format!("Damage {percent}%"),
+            None => String::new(),
+        };
+        txt.sections[0].style.color = match damage {
+            Some(d) => Color::rgb(0.9, 0.9 * (1.0 - d.0), 0.9 * (1.0 - d.0)),
+            None => Color::rgb(0.9, 0.9, 0.9),
+        };
+    }
+}
+
+/// Once the lead player has finished show the finishing order, along with the
+/// reason for every penalty that was handed out.
+fn update_results(
+    racers: Query<(&Racer, &Name, Option<&Player>)>,
+    mut label: Query<&mut Text, With<Results>>,
+    mut last: Local<Option<(usize, usize)>>,
+) {
+    let mut txt = label.single_mut();
+
+    // Only rebuild the results when someone finishes or is penalised
+    let finished = racers
+        .iter()
+        .any(|(r, _, p)| p.is_some_and(|p| p.is_lead()) && r.finish_time.is_some());
+    let state = finished.then(|| {
+        racers
+            .iter()
+            .fold((0, 0), |(finishers, penalties), (r, ..)| {
+                (
+                    finishers + r.finish_time.is_some() as usize,
+                    penalties + r.penalties.len(),
+                )
+            })
+    });
+    if *last == state && !txt.is_added() {
+        return;
+    }
+    *last = state;
 
-    needle.rotation = Quat::from_rotation_z(vp.0.length() / 100.0);
+    if state.is_none() {
+        txt.sections[0].value = String::new();
+        return;
+    }
+
+    let mut order = racers.iter().collect::<Vec<_>>();
+    order.sort_by_key(|(r, ..)| r.position);
+
+    let mut results = String::from("Results\n");
+    for (n, (r, name, _)) in order.iter().enumerate() {
+        match r.finish_time {
+            Some(_) => writeln!(results, "{}. {name}", n + 1),
+            None => writeln!(results, "-- {name}"),
+        }
+        .unwrap();
+        for penalty in &r.penalties {
+            writeln!(results, "      {penalty}").unwrap();
+        }
+    }
+    txt.sections[0].value = results;
+}
+
+/// Warn the lead player when they are driving the wrong way round the track.
+fn update_wrong_way(
+    player: Query<(&Racer, &Player)>,
+    mut label: Query<&mut Visibility, With<WrongWay>>,
+) {
+    let wrong_way = player
+        .iter()
+        .any(|(car, p)| p.is_lead() && car.is_wrong_way());
+    let visibility = if wrong_way {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+
+    let mut label = label.single_mut();
+    if *label != visibility {
+        *label = visibility;
+    }
 }
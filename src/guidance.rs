@@ -0,0 +1,339 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+//! A map of how far each point is from the edge of the track.
+//!
+//! This module deliberately depends on nothing else in the crate (except
+//! [crate::track]) so that the benchmarks can include it directly.
+
+use bevy::{log, math::Vec2};
+use std::path::PathBuf;
+use thiserror::Error;
+
+use crate::track::{Track, TrackError};
+
+/// Number of off-track tiles added around the edge of the map so that the
+/// edge of the map is treated as grass.
+const PADDING: usize = 2;
+
+/// Number of samples across each tile. Sampling only at the centre of each
+/// tile is too coarse: a track two tiles wide would have no middle.
+const SAMPLES_PER_TILE: usize = 4;
+
+/// Steepness of the change from track to grass. The field is a logistic
+/// function of the signed distance to the edge and this value makes it a
+/// close match for the blurred image that was used before (so the existing
+/// thresholds keep their meaning).
+const SHARPNESS: f32 = 1.7;
+
+/// Large enough to behave as infinity in the distance transform without
+/// upsetting the arithmetic.
+const FAR: f64 = 1.0e12;
+
+/// Change this whenever the contents of the cache files change.
+const CACHE_VERSION: u32 = 1;
+
+const CACHE_MAGIC: &[u8; 4] = b"TDRG";
+
+#[derive(Debug, Error)]
+pub enum GuidanceError {
+    #[error(transparent)]
+    Track(#[from] TrackError),
+    #[error("No cache directory for this platform")]
+    NoCacheDir,
+    #[error("Could not access guidance cache: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Guidance cache is corrupt")]
+    Corrupt,
+}
+
+#[derive(bevy::prelude::Resource)]
+pub struct GuidanceField {
+    /// Size of the map (in tiles) and of each tile (in world units).
+    width: usize,
+    height: usize,
+    tile_size: Vec2,
+    /// One entry per tile, set if the tile is part of the track.
+    tiles: Vec<bool>,
+    /// Signed distance, in tiles, from each sample point to the edge of the
+    /// track (positive on the track). Includes the padding.
+    distance: Vec<f32>,
+}
+
+impl GuidanceField {
+    /// Generate the field from the track layer of the map (without using the
+    /// cache).
+    #[allow(dead_code)] // Used by the benchmarks
+    pub fn from_map(map: &tiled::Map) -> Result<Self, GuidanceError> {
+        let mut field = Self::from_tiles(map)?;
+        field.generate();
+        Ok(field)
+    }
+
+    /// Load the field from the cache, generating (and caching) it if needed.
+    pub fn load(map: &tiled::Map) -> Result<Self, GuidanceError> {
+        let mut field = Self::from_tiles(map)?;
+        match field.load_cache() {
+            Ok(distance) => field.distance = distance,
+            Err(e) => {
+                if !matches!(&e, GuidanceError::Io(e) if e.kind() == std::io::ErrorKind::NotFound) {
+                    log::warn!("{e}, regenerating guidance field");
+                }
+                field.generate();
+                if let Err(e) = field.save_cache() {
+                    log::warn!("{e}");
+                }
+            }
+        }
+        Ok(field)
+    }
+
+    fn generate(&mut self) {
+        self.distance = signed_distance(&self.samples(), self.samples_wide());
+    }
+
+    fn from_tiles(map: &tiled::Map) -> Result<Self, GuidanceError> {
+        let track = Track::from_map(map)?;
+        let tiles = (0..track.height as i32)
+            .flat_map(|y| (0..track.width as i32).map(move |x| (x, y)))
+            .map(|(x, y)| track.contains(x, y))
+            .collect();
+
+        Ok(Self {
+            width: track.width,
+            height: track.height,
+            tile_size: Vec2::new(map.tile_width as f32, map.tile_height as f32),
+            tiles,
+            distance: Vec::new(),
+        })
+    }
+
+    fn samples_wide(&self) -> usize {
+        (self.width + 2 * PADDING) * SAMPLES_PER_TILE
+    }
+
+    fn samples_high(&self) -> usize {
+        (self.height + 2 * PADDING) * SAMPLES_PER_TILE
+    }
+
+    /// Expand the tiles (and the padding) into a grid of samples, set if the
+    /// sample is on the track.
+    fn samples(&self) -> Vec<bool> {
+        let (w, h) = (self.samples_wide(), self.samples_high());
+        let tile = |n: usize| (n / SAMPLES_PER_TILE).checked_sub(PADDING);
+
+        (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .map(|(x, y)| match (tile(x), tile(y)) {
+                (Some(x), Some(y)) if x < self.width && y < self.height => {
+                    self.tiles[y * self.width + x]
+                }
+                _ => false,
+            })
+            .collect()
+    }
+
+    /// Convert a world position into a tile position (where whole numbers are
+    /// at the top-left of a tile).
+    fn to_tiles(&self, pos: &Vec2) -> Vec2 {
+        Vec2::new(
+            pos.x / self.tile_size.x + self.width as f32 / 2.0,
+            -pos.y / self.tile_size.y + self.height as f32 / 2.0,
+        )
+    }
+
+    /// Signed distance, in tiles, from `pos` to the edge of the track.
+    ///
+    /// The distances are stored for a grid of sample points and interpolated
+    /// between them. Anything beyond the edge of the map is `None`.
+    pub fn distance(&self, pos: &Vec2) -> Option<f32> {
+        let p = (self.to_tiles(pos) + Vec2::splat(PADDING as f32)) * SAMPLES_PER_TILE as f32
+            - Vec2::splat(0.5);
+        let (w, h) = (self.samples_wide(), self.samples_high());
+        if p.x < 0.0 || p.y < 0.0 || p.x >= (w - 1) as f32 || p.y >= (h - 1) as f32 {
+            return None;
+        }
+
+        let (x, y) = (p.x as usize, p.y as usize);
+        let (fx, fy) = (p.x.fract(), p.y.fract());
+        let at = |x: usize, y: usize| self.distance[y * w + x];
+        let top = at(x, y) + (at(x + 1, y) - at(x, y)) * fx;
+        let bottom = at(x, y + 1) + (at(x + 1, y + 1) - at(x, y + 1)) * fx;
+        Some(top + (bottom - top) * fy)
+    }
+
+    /// Check whether `pos` is on a track tile.
+    ///
+    /// The field is smooth so it strays a little way beyond the edge of the
+    /// track; anything that must stay on the track should check this as well
+    /// as the value of the field.
+    pub fn is_on_track(&self, pos: &Vec2) -> bool {
+        let p = self.to_tiles(pos);
+        p.x >= 0.0
+            && p.y >= 0.0
+            && (p.x as usize) < self.width
+            && (p.y as usize) < self.height
+            && self.tiles[p.y as usize * self.width + p.x as usize]
+    }
+
+    /// Sample the field. The result ranges from 0 (far from the track) to
+    /// 255 (well inside the track) and is 128 at the edge of the track.
+    pub fn get(&self, pos: &Vec2) -> i32 {
+        match self.distance(pos) {
+            Some(d) => (255.0 / (1.0 + (-SHARPNESS * d).exp())) as i32,
+            None => 0,
+        }
+    }
+
+    /// Cheap, stable (FNV-1a) hash of everything the field is generated from.
+    fn hash(&self) -> u64 {
+        let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+        let mut feed = |bytes: &[u8]| {
+            for b in bytes {
+                hash = (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3);
+            }
+        };
+        feed(&CACHE_VERSION.to_le_bytes());
+        feed(&(SAMPLES_PER_TILE as u32).to_le_bytes());
+        feed(&(self.width as u32).to_le_bytes());
+        feed(&(self.height as u32).to_le_bytes());
+        for t in &self.tiles {
+            feed(&[*t as u8]);
+        }
+        hash
+    }
+
+    fn cache_path(&self) -> Result<PathBuf, GuidanceError> {
+        let mut path = dirs::cache_dir().ok_or(GuidanceError::NoCacheDir)?;
+        path.push("tdr2024");
+        path.push(format!("guidance-{:016x}.bin", self.hash()));
+        Ok(path)
+    }
+
+    fn load_cache(&self) -> Result<Vec<f32>, GuidanceError> {
+        let path = self.cache_path()?;
+        let bytes = std::fs::read(&path)?;
+        log::debug!("Loading guidance field from {}", path.display());
+
+        let expected = self.samples_wide() * self.samples_high();
+        let (header, body) = bytes.split_at_checked(8).ok_or(GuidanceError::Corrupt)?;
+        if &header[0..4] != CACHE_MAGIC
+            || header[4..8] != CACHE_VERSION.to_le_bytes()
+            || body.len() != expected * 4
+        {
+            return Err(GuidanceError::Corrupt);
+        }
+
+        Ok(body
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect())
+    }
+
+    fn save_cache(&self) -> Result<(), GuidanceError> {
+        let path = self.cache_path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut bytes = Vec::with_capacity(8 + 4 * self.distance.len());
+        bytes.extend_from_slice(CACHE_MAGIC);
+        bytes.extend_from_slice(&CACHE_VERSION.to_le_bytes());
+        for d in &self.distance {
+            bytes.extend_from_slice(&d.to_le_bytes());
+        }
+        std::fs::write(&path, bytes)?;
+        log::debug!("Saved guidance field to {}", path.display());
+        Ok(())
+    }
+}
+
+/// Signed distance, in tiles, from each sample to the edge of the track.
+///
+/// The edge is half way between a track sample and the nearest grass sample
+/// (and vice versa).
+fn signed_distance(samples: &[bool], width: usize) -> Vec<f32> {
+    let grass = samples.iter().map(|s| !s).collect::<Vec<_>>();
+    let to_grass = distance_transform(&grass, width);
+    let to_track = distance_transform(samples, width);
+
+    samples
+        .iter()
+        .zip(to_grass.iter().zip(to_track.iter()))
+        .map(|(on_track, (g, t))| {
+            let d = if *on_track {
+                g.sqrt() - 0.5
+            } else {
+                0.5 - t.sqrt()
+            };
+            d as f32 / SAMPLES_PER_TILE as f32
+        })
+        .collect()
+}
+
+/// Squared euclidean distance from each cell to the nearest `seed`.
+///
+/// This is the separable algorithm from Felzenszwalb and Huttenlocher,
+/// "Distance Transforms of Sampled Functions", and runs in linear time.
+fn distance_transform(seeds: &[bool], width: usize) -> Vec<f64> {
+    let height = seeds.len() / width;
+    let mut grid = seeds
+        .iter()
+        .map(|s| if *s { 0.0 } else { FAR })
+        .collect::<Vec<_>>();
+
+    let mut f = Vec::new();
+    let mut d = Vec::new();
+    for x in 0..width {
+        f.clear();
+        f.extend((0..height).map(|y| grid[y * width + x]));
+        d.resize(height, 0.0);
+        distance_transform_1d(&f, &mut d);
+        for (y, v) in d.iter().enumerate() {
+            grid[y * width + x] = *v;
+        }
+    }
+    for row in grid.chunks_mut(width) {
+        f.clear();
+        f.extend_from_slice(row);
+        distance_transform_1d(&f, row);
+    }
+
+    grid
+}
+
+/// One dimensional distance transform: the lower envelope of the parabolas
+/// rooted at each sample.
+fn distance_transform_1d(f: &[f64], d: &mut [f64]) {
+    let n = f.len();
+    let mut v = vec![0; n];
+    let mut z = vec![0.0; n + 1];
+    let mut k = 0;
+    z[0] = f64::NEG_INFINITY;
+    z[1] = f64::INFINITY;
+
+    let intersect = |q: usize, p: usize| {
+        let (qf, pf) = (q as f64, p as f64);
+        ((f[q] + qf * qf) - (f[p] + pf * pf)) / (2.0 * (qf - pf))
+    };
+
+    for q in 1..n {
+        let mut s = intersect(q, v[k]);
+        while s <= z[k] {
+            k -= 1;
+            s = intersect(q, v[k]);
+        }
+        k += 1;
+        v[k] = q;
+        z[k] = s;
+        z[k + 1] = f64::INFINITY;
+    }
+
+    k = 0;
+    for (q, out) in d.iter_mut().enumerate() {
+        while z[k + 1] < q as f64 {
+            k += 1;
+        }
+        let dq = q as f64 - v[k] as f64;
+        *out = dq * dq + f[v[k]];
+    }
+}
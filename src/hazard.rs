@@ -0,0 +1,131 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+#![allow(clippy::type_complexity)]
+
+use bevy::{log, prelude::*};
+
+use crate::{physics, GameState, Preferences, Racer};
+
+/// How long, in seconds, a car keeps sliding after crossing a hazard.
+const SLIP_TIME: f32 = 1.5;
+
+/// Range of the yaw rate (radians per second) added to a slipping car.
+const MIN_YAW_RATE: f32 = 1.0;
+const MAX_YAW_RATE: f32 = 3.0;
+
+#[derive(Default)]
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<HazardContact>().add_systems(
+            Update,
+            (
+                detect_hazards.after(physics::apply_velocity),
+                apply_slip
+                    .before(physics::apply_velocity)
+                    .run_if(in_state(GameState::Game)),
+                debug_hazards.after(detect_hazards),
+            ),
+        );
+    }
+}
+
+/// A patch of the track (such as an oil slick) with no grip.
+///
+/// The area affected is given by the [physics::ShapeBox] of the hazard. The
+/// radius is a rough summary of that area for anything (such as the AI) that
+/// wants to steer around it.
+#[derive(Component, Debug)]
+pub struct Hazard {
+    pub radius: f32,
+}
+
+/// A car has driven onto a hazard.
+#[derive(Event, Clone, Debug)]
+pub struct HazardContact {
+    pub car: Entity,
+    pub hazard: Entity,
+    pub centre: Vec2,
+    pub radius: f32,
+}
+
+/// A car that has lost grip.
+#[derive(Component, Debug)]
+pub struct Slipping {
+    remaining: f32,
+    yaw_rate: f32,
+}
+
+impl Slipping {
+    /// Fraction of the normal grip, recovering as the tyres clean up.
+    pub fn grip(&self) -> f32 {
+        1.0 - (self.remaining / SLIP_TIME).clamp(0.0, 1.0)
+    }
+}
+
+fn detect_hazards(
+    mut commands: Commands,
+    cars: Query<(Entity, &physics::CollisionBox, &Transform, Has<Slipping>), With<Racer>>,
+    hazards: Query<(Entity, &physics::ShapeBox, &Hazard, &Transform)>,
+    mut events: EventWriter<HazardContact>,
+) {
+    for (car, physics::CollisionBox(poly), xform, slipping) in cars.iter() {
+        if slipping {
+            continue;
+        }
+        let car_box = poly.transform(xform);
+
+        for (hazard, physics::ShapeBox(poly), Hazard { radius }, hazard_xform) in hazards.iter() {
+            if car_box.is_touching(&poly.transform(hazard_xform)) {
+                let direction = if fastrand::bool() { 1.0 } else { -1.0 };
+                commands.entity(car).insert(Slipping {
+                    remaining: SLIP_TIME,
+                    yaw_rate: direction
+                        * (MIN_YAW_RATE + fastrand::f32() * (MAX_YAW_RATE - MIN_YAW_RATE)),
+                });
+                events.send(HazardContact {
+                    car,
+                    hazard,
+                    centre: hazard_xform.translation.truncate(),
+                    radius: *radius,
+                });
+                break;
+            }
+        }
+    }
+}
+
+/// Spin slipping cars until they regain grip.
+fn apply_slip(
+    mut commands: Commands,
+    mut cars: Query<(Entity, &mut Slipping, &mut physics::Angle, &mut Transform)>,
+    time: Res<Time>,
+) {
+    let delta = time.delta_seconds();
+
+    for (car, mut slip, mut a, mut t) in cars.iter_mut() {
+        a.0 += delta * slip.yaw_rate * (1.0 - slip.grip());
+        a.normalize();
+        t.rotation = a.to_quat();
+
+        slip.remaining -= delta;
+        if slip.remaining <= 0.0 {
+            commands.entity(car).remove::<Slipping>();
+        }
+    }
+}
+
+fn debug_hazards(
+    mut events: EventReader<HazardContact>,
+    prefs: Res<Preferences>,
+    mut gizmos: Gizmos,
+) {
+    for e in events.read() {
+        log::debug!("{:?} hit hazard {:?}", e.car, e.hazard);
+        if prefs.debug_low() {
+            gizmos.circle_2d(e.centre, e.radius, Color::ORANGE);
+        }
+    }
+}
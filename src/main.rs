@@ -5,23 +5,42 @@
 
 use bevy::{log, prelude::*, render::camera::ScalingMode, window};
 use bevy_ecs_tilemap::prelude as ecs_tilemap;
-use clap::Parser;
-use std::f32::consts::PI;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::{cmp::Ordering, path::PathBuf};
 
+mod ai;
 mod assets;
+mod audio;
+mod controls;
+mod damage;
 mod dashboard;
 mod editor;
 mod geometry;
+mod guidance;
+mod hazard;
 mod mapping;
 mod menu;
+mod minimap;
 mod objectmap;
+mod penalty;
 mod physics;
+mod racing_line;
+mod respawn;
+mod settings;
+mod skidmarks;
+mod synth;
 mod tilemap;
+mod track;
 mod util;
+mod validate;
 
 #[derive(Clone, Debug, Parser, Resource)]
 #[command(author, version, about, long_about = None)]
 struct Preferences {
+    /// Gamepad dead zone, as a fraction of the full stick travel
+    #[arg(long, default_value_t = 0.1)]
+    dead_zone: f32,
+
     /// Turn debugging visualizations on
     #[arg(short, long, action = clap::ArgAction::Count)]
     debug: u8,
@@ -37,6 +56,18 @@ struct Preferences {
     /// Enable windowed mode (for debugging try: -wdd)
     #[arg(short, long)]
     window: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum Command {
+    /// Check a level for mistakes (exits with an error if any are found)
+    Validate {
+        /// The level to check
+        level: PathBuf,
+    },
 }
 
 impl Preferences {
@@ -50,35 +81,68 @@ impl Preferences {
 }
 
 fn main() {
-    let args = Preferences::parse();
+    let matches = Preferences::command().get_matches();
+    let mut args = Preferences::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(Command::Validate { level }) = &args.command {
+        std::process::exit(validate::validate(level));
+    }
+    let settings = settings::Settings::load();
+    let command_line = settings.apply(&mut args, &matches);
+    let display_mode = if args.window {
+        menu::DisplayMode::Windowed
+    } else {
+        menu::DisplayMode::Fullscreen
+    };
 
     App::new()
         .add_plugins((
             DefaultPlugins.set(WindowPlugin {
                 primary_window: Some(Window {
                     title: "TDR2024 - Orcombe Point edition".to_string(),
-                    resolution: (1280.0, 720.0).into(),
-                    present_mode: window::PresentMode::AutoVsync,
-                    mode: if args.window {
-                        window::WindowMode::default()
-                    } else {
-                        window::WindowMode::BorderlessFullscreen
-                    },
+                    resolution: (settings.resolution.0 as f32, settings.resolution.1 as f32).into(),
+                    present_mode: present_mode(settings.vsync),
+                    mode: window_mode(display_mode),
                     ..default()
                 }),
                 ..default()
             }),
+            ecs_tilemap::TilemapPlugin,
+            tilemap::TiledMapPlugin,
+        ))
+        .add_plugins((
+            ai::Plugin,
             assets::Plugin,
+            audio::Plugin,
+            controls::Plugin,
+            damage::Plugin,
             editor::Plugin,
-            ecs_tilemap::TilemapPlugin,
+            hazard::Plugin,
             mapping::Plugin,
             menu::MenuPlugin,
             objectmap::Plugin,
-            tilemap::TiledMapPlugin,
+            penalty::Plugin,
+            racing_line::Plugin,
+            settings::Plugin,
+            skidmarks::Plugin,
             dashboard::Plugin,
         ))
+        .add_plugins((minimap::Plugin, respawn::Plugin))
+        .add_event::<physics::CarCarCollision>()
+        .add_event::<physics::CarSceneryCollision>()
         .insert_resource(ClearColor(Color::rgb_linear(0.153, 0.682, 0.376)))
         .insert_resource(args)
+        .insert_resource(command_line)
+        .insert_resource(menu::Volume(settings.volume))
+        .insert_resource(display_mode)
+        .insert_resource(settings.resolution)
+        .insert_resource(settings.vsync)
+        .insert_resource(settings.view_width)
+        .insert_resource(settings.minimap_corner)
+        .insert_resource(settings.minimap_opacity)
+        .insert_resource(settings.digital_speedo)
+        .insert_resource(settings.bindings)
+        .insert_resource(settings.difficulty)
+        .insert_resource(settings.rubber_banding)
         .add_systems(Startup, (spawn_camera, load_maps))
         .add_systems(OnEnter(GameState::Game), hide_cursor)
         .add_systems(
@@ -94,19 +158,28 @@ fn main() {
             Update,
             (
                 trigger_menu,
-                handle_human_player.run_if(in_state(GameState::Game)),
-                handle_ai_players.run_if(in_state(GameState::Game)),
+                apply_display_settings,
+                handle_human_player
+                    .after(controls::read_controls)
+                    .run_if(in_state(GameState::Game)),
+                ai::handle_ai_players.run_if(in_state(GameState::Game)),
+                detect_wrong_way
+                    .after(ai::handle_ai_players)
+                    .before(handle_lap_counter),
                 handle_lap_counter,
+                update_race_order.after(handle_lap_counter),
                 physics::apply_velocity
-                    .after(handle_ai_players)
+                    .after(ai::handle_ai_players)
                     .after(handle_human_player),
                 physics::apply_friction.after(physics::apply_velocity),
+                physics::simulate_engine.after(physics::apply_friction),
                 track_player.after(physics::apply_velocity),
                 physics::collision_detection
                     .after(physics::apply_velocity)
                     .after(handle_human_player)
-                    .after(handle_ai_players),
+                    .after(ai::handle_ai_players),
                 physics::fixed_collision_detection.after(physics::collision_detection),
+                physics::debug_collisions.after(physics::fixed_collision_detection),
             ),
         )
         .run();
@@ -136,35 +209,219 @@ fn hide_cursor(mut q: Query<&mut Window>) {
     }
 }
 
+/// Number of laps in a race.
+const RACE_LAPS: u32 = 5;
+
+/// Time (in seconds) a car must spend driving against the racing line
+/// before it is warned that it is going the wrong way.
+const WRONG_WAY_TIME: f32 = 1.0;
+
+/// Speed below which driving against the racing line doesn't count as going
+/// the wrong way (e.g. while turning round after a spin).
+const WRONG_WAY_SPEED: f32 = 80.0;
+
+/// A checkpoint, numbered in the order they must be driven. Checkpoint 0 is
+/// the start/finish line.
 #[derive(Component, Debug)]
-struct LapCounter(u32);
+struct LapCounter(usize);
 
 #[derive(Component, Debug)]
 struct LevelComponent;
 
+/// Marks a car as human controlled.
+///
+/// The value is the seat number. Seat 0 is driven from the keyboard (and the
+/// first gamepad) and is the car followed by the camera and the dashboard.
 #[derive(Component, Debug)]
-struct Player;
+struct Player(usize);
+
+impl Player {
+    fn is_lead(&self) -> bool {
+        self.0 == 0
+    }
+}
 
 #[derive(Component, Debug, Default)]
 struct Racer {
     lap_count: u32,
-    sub_count: u32,
-    start_finish: u32,
 
+    /// Place in the race (starting from 1)
+    position: usize,
+
+    /// Lap and split times
+    times: LapTimes,
+
+    /// Number of checkpoints passed since the start of the race (going back
+    /// through a checkpoint takes one off again), or `None` until the car
+    /// first crosses the start line
+    progress: Option<i32>,
+
+    /// How long the car has been going the wrong way
+    wrong_way: f32,
+
+    /// Time left to serve for the penalties, and the penalties themselves
     penalty: f32,
+    penalties: Vec<penalty::Penalty>,
+
+    /// Where the car was last seen on the track, and whether it has been out
+    /// on the grass since then
     last_tile: Option<Vec2>,
+    on_grass: bool,
+
+    /// Progress along the racing line: the point nearest the car, which is
+    /// also where the search for the next nearest point starts from
+    waypoint: usize,
+
+    /// Where to put the car back on the track if it gets lost: the centre of
+    /// the last checkpoint passed (or the grid), pointing along the track
+    last_checkpoint: Option<Transform>,
+
+    /// Time (since startup) at which the car finished the race
+    finish_time: Option<f32>,
+}
+
+impl Racer {
+    fn is_wrong_way(&self) -> bool {
+        self.wrong_way >= WRONG_WAY_TIME
+    }
+}
+
+/// Lap times for a racer. All times, except `start`, are measured from the
+/// start of the lap.
+#[derive(Debug, Default)]
+struct LapTimes {
+    /// Time (since startup) at which the current lap started
+    start: Option<f32>,
+    last: Option<f32>,
+    best: Option<f32>,
+
+    /// Time at which each checkpoint was passed on the current lap and on
+    /// the best lap
+    splits: Vec<(usize, f32)>,
+    best_splits: Vec<(usize, f32)>,
+
+    /// Time at which each point of the racing line was reached on the
+    /// current lap and on the best lap, along with the section of the lap
+    /// (the last checkpoint passed) the car was in at the time
+    trace: Vec<Option<(usize, f32)>>,
+    best_trace: Vec<Option<(usize, f32)>>,
+
+    /// How far behind (or, if negative, ahead of) the best lap the car is
+    delta: Option<f32>,
+}
+
+impl LapTimes {
+    /// Time spent on the current lap so far.
+    fn current(&self, now: f32) -> Option<f32> {
+        self.start.map(|start| now - start)
+    }
+
+    fn start_lap(&mut self, now: f32) {
+        self.start = Some(now);
+        self.splits.clear();
+        self.trace.clear();
+    }
+
+    fn checkpoint(&mut self, n: usize, now: f32) {
+        let Some(time) = self.current(now) else {
+            return;
+        };
+        // Only the last time through a checkpoint counts
+        self.splits.retain(|(m, _)| *m != n);
+        self.splits.push((n, time));
+        self.delta = self
+            .best_splits
+            .iter()
+            .find(|(m, _)| *m == n)
+            .map(|(_, best)| time - best);
+    }
+
+    fn finish_lap(&mut self, now: f32) {
+        let Some(time) = self.current(now) else {
+            return;
+        };
+        self.delta = self.best.map(|best| time - best);
+        self.last = Some(time);
+        if self.best.is_none_or(|best| time < best) {
+            self.best = Some(time);
+            self.best_splits = std::mem::take(&mut self.splits);
+            self.best_trace = std::mem::take(&mut self.trace);
+        }
+        self.start_lap(now);
+    }
+
+    /// Compare the current lap against the best lap as the car goes round.
+    ///
+    /// The points either side of the start line are reached at both ends of
+    /// the lap so they are only compared if the car is in the same section
+    /// of the lap as it was on the best lap.
+    fn follow(&mut self, waypoint: usize, section: usize, now: f32) {
+        let Some(time) = self.current(now) else {
+            return;
+        };
+        if self.trace.len() <= waypoint {
+            self.trace.resize(waypoint + 1, None);
+        }
+        if self.trace[waypoint].is_none() {
+            self.trace[waypoint] = Some((section, time));
+        }
+        if let Some(Some((best_section, best))) = self.best_trace.get(waypoint) {
+            if *best_section == section {
+                self.delta = Some(time - best);
+            }
+        }
+    }
 }
 
 #[derive(Component, Default, Debug)]
 struct Track;
 
-fn spawn_camera(mut commands: Commands) {
+fn spawn_camera(mut commands: Commands, view_width: Res<menu::ViewWidth>) {
     let mut camera = Camera2dBundle::default();
-    // Request a constant width projection. 24 is the width in world units.
-    camera.projection.scaling_mode = ScalingMode::FixedHorizontal(1920.0);
+    // Request a constant width projection (the width is in world units).
+    camera.projection.scaling_mode = ScalingMode::FixedHorizontal(view_width.0 as f32);
     commands.spawn(camera);
 }
 
+fn window_mode(mode: menu::DisplayMode) -> window::WindowMode {
+    match mode {
+        menu::DisplayMode::Fullscreen => window::WindowMode::BorderlessFullscreen,
+        menu::DisplayMode::Windowed => window::WindowMode::default(),
+    }
+}
+
+fn present_mode(vsync: menu::Vsync) -> window::PresentMode {
+    if vsync.0 {
+        window::PresentMode::AutoVsync
+    } else {
+        window::PresentMode::AutoNoVsync
+    }
+}
+
+/// Apply any changes to the display settings to the window and camera.
+fn apply_display_settings(
+    display_mode: Res<menu::DisplayMode>,
+    resolution: Res<menu::Resolution>,
+    vsync: Res<menu::Vsync>,
+    view_width: Res<menu::ViewWidth>,
+    mut windows: Query<&mut Window>,
+    mut projections: Query<&mut OrthographicProjection>,
+) {
+    if display_mode.is_changed() || resolution.is_changed() || vsync.is_changed() {
+        for mut w in windows.iter_mut() {
+            w.mode = window_mode(*display_mode);
+            w.resolution.set(resolution.0 as f32, resolution.1 as f32);
+            w.present_mode = present_mode(*vsync);
+        }
+    }
+
+    if view_width.is_changed() {
+        for mut projection in projections.iter_mut() {
+            projection.scaling_mode = ScalingMode::FixedHorizontal(view_width.0 as f32);
+        }
+    }
+}
+
 fn load_maps(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
@@ -191,81 +448,44 @@ fn set_game_state(mut game_state: ResMut<NextState<GameState>>) {
     game_state.set(GameState::Game);
 }
 
-fn trigger_menu(input: Res<Input<KeyCode>>, mut game_state: ResMut<NextState<GameState>>) {
-    if input.pressed(KeyCode::Escape) {
-        game_state.set(GameState::Menu);
-    }
-}
-
-fn handle_human_player(
-    mut query: Query<(
-        &mut physics::Angle,
-        &mut physics::Velocity,
-        &mut Transform,
-        &mut Racer,
-        With<Player>,
-    )>,
-    time: Res<Time>,
+fn trigger_menu(
+    bindings: Res<controls::Bindings>,
     input: Res<Input<KeyCode>>,
+    gamepads: Res<Gamepads>,
+    buttons: Res<Input<GamepadButton>>,
     mut game_state: ResMut<NextState<GameState>>,
 ) {
-    let delta = time.delta_seconds();
-
-    let Some((mut a, mut v, mut t, mut r, _)) = query.iter_mut().next() else {
-        return;
-    };
-
-    if r.lap_count >= 5 {
+    let menu = bindings.pressed(controls::Action::Menu, Some(&input), None)
+        || gamepads
+            .iter()
+            .any(|pad| bindings.pressed(controls::Action::Menu, None, Some((pad, &buttons))));
+    if menu {
         game_state.set(GameState::Menu);
-        return;
     }
-
-    if r.penalty > 0.0 {
-        r.penalty = if r.penalty < delta {
-            0.0
-        } else {
-            r.penalty - delta
-        };
-        return;
-    }
-
-    if input.pressed(KeyCode::Z) {
-        a.0 += delta * 3.0;
-    }
-    if input.pressed(KeyCode::X) {
-        a.0 -= delta * 3.0;
-    }
-    if input.pressed(KeyCode::ShiftRight) || input.pressed(KeyCode::ShiftLeft) {
-        v.0 += delta * 560.0 * Vec2::from_angle(a.0);
-    }
-
-    a.normalize();
-    t.rotation = a.to_quat();
 }
 
-fn handle_ai_players(
+fn handle_human_player(
     mut query: Query<(
         &mut physics::Angle,
         &mut physics::Velocity,
         &mut Transform,
         &mut Racer,
-        Without<Player>,
+        &controls::Controls,
+        Option<&damage::Damage>,
+        Option<&hazard::Slipping>,
+        With<Player>,
     )>,
     time: Res<Time>,
-    guide: Option<Res<mapping::GuidanceField>>,
-    prefs: Res<Preferences>,
-    mut gizmos: Gizmos,
+    mut game_state: ResMut<NextState<GameState>>,
 ) {
-    let Some(guide) = guide else {
-        return;
-    };
-
     let delta = time.delta_seconds();
 
-    for (mut a, mut v, mut t, mut r, _) in query.iter_mut() {
-        if r.lap_count >= 5 {
-            continue;
+    for (mut a, mut v, mut t, mut r, controls, damage, slipping, _) in query.iter_mut() {
+        if r.lap_count >= RACE_LAPS {
+            game_state.set(GameState::Menu);
+            return;
         }
+
         if r.penalty > 0.0 {
             r.penalty = if r.penalty < delta {
                 0.0
@@ -274,44 +494,12 @@ fn handle_ai_players(
             };
             continue;
         }
-        let pos = Vec2::new(t.translation.x, t.translation.y);
-
-        let left_whisker = pos + (425.0 * Vec2::from_angle(a.0 + (PI / 12.)));
-        let left_pixel = guide.get(&left_whisker);
-        let right_whisker = pos + (425.0 * Vec2::from_angle(a.0 - (PI / 12.)));
-        let right_pixel = guide.get(&right_whisker);
-
-        let left_whisker2 = pos + (200.0 * Vec2::from_angle(a.0 + (PI / 6.)));
-        let left_pixel2 = guide.get(&left_whisker2);
-        let right_whisker2 = pos + (200.0 * Vec2::from_angle(a.0 - (PI / 6.)));
-        let right_pixel2 = guide.get(&right_whisker2);
-
-        let front_whisker = pos + (425.0 * Vec2::from_angle(a.0));
-        let front_pixel = guide.get(&front_whisker);
-
-        if prefs.debug_high() {
-            for v in [
-                left_whisker,
-                right_whisker,
-                left_whisker2,
-                right_whisker2,
-                front_whisker,
-            ] {
-                gizmos.circle_2d(v, 2.0, Color::BLUE);
-                gizmos.line_2d(pos, v, Color::BLUE);
-            }
-        }
 
-        if ((left_pixel - 10) > right_pixel) || ((left_pixel2 - 10) > right_pixel2) {
-            a.0 += delta * 3.0;
-        }
-        if ((right_pixel - 10) > left_pixel) || ((right_pixel2 - 10) > left_pixel2) {
-            a.0 -= delta * 3.0;
-        }
-
-        if front_pixel > 50 {
-            v.0 += delta * 580.0 * Vec2::from_angle(a.0);
-        }
+        let grip = slipping.map_or(1.0, |s| s.grip());
+        let steering = grip * damage.map_or(1.0, |d| d.steering());
+        let acceleration = grip * damage.map_or(1.0, |d| d.acceleration());
+        a.0 += delta * 3.0 * steering * controls.steer;
+        v.0 += delta * 560.0 * acceleration * controls.throttle * Vec2::from_angle(a.0);
 
         a.normalize();
         t.rotation = a.to_quat();
@@ -320,7 +508,17 @@ fn handle_ai_players(
 
 fn handle_lap_counter(
     checkpoints: Query<(&physics::ShapeBox, &LapCounter, &Transform)>,
-    mut cars: Query<(&physics::CollisionBox, &mut Racer, &Transform)>,
+    mut cars: Query<(
+        Entity,
+        &physics::CollisionBox,
+        &mut Racer,
+        &Transform,
+        &physics::Angle,
+        &physics::Velocity,
+    )>,
+    line: Option<Res<racing_line::RacingLine>>,
+    mut infringements: EventWriter<penalty::Infringement>,
+    time: Res<Time>,
     prefs: Res<Preferences>,
     mut gizmos: Gizmos,
 ) {
@@ -330,36 +528,176 @@ fn handle_lap_counter(
         }
     }
 
-    let mask = checkpoints
-        .iter()
-        .map(|(_, c, _)| c.0)
-        .reduce(|a, b| a | b)
-        .unwrap_or(0);
+    let count = checkpoints.iter().count() as i32;
 
-    for (physics::CollisionBox(poly), mut car, xform) in cars.iter_mut() {
-        let car_box = poly.transform(xform);
+    for (entity, physics::CollisionBox(poly), mut car, car_xform, a, v) in cars.iter_mut() {
+        let car_box = poly.transform(car_xform);
+        let along = line.as_ref().map(|line| line.direction(car.waypoint));
 
-        for (physics::ShapeBox(poly), LapCounter(bit), xform) in checkpoints.iter() {
-            if car_box.is_touching(&poly.transform(xform)) {
-                if car.start_finish == 0 {
-                    car.start_finish = *bit;
+        for (physics::ShapeBox(poly), LapCounter(n), xform) in checkpoints.iter() {
+            if !car_box.is_touching(&poly.transform(xform)) {
+                continue;
+            }
+
+            let n = *n as i32;
+            let heading = v.0.try_normalize().unwrap_or(Vec2::from_angle(a.0));
+            let Some(progress) = car.progress else {
+                // The race starts when the car first crosses the start line
+                if n == 0 {
+                    car.progress = Some(0);
+                    car.times.start_lap(time.elapsed_seconds());
+                    car.last_checkpoint = Some(respawn_point(xform, line.as_deref(), heading));
+                }
+                continue;
+            };
+            let ahead = (n - progress).rem_euclid(count);
+            if ahead == 0 {
+                continue;
+            }
+
+            // Work out whether the car carried on forward to reach this
+            // checkpoint (perhaps skipping some) or has turned back. The
+            // racing line tells us which way the car is going but, if there
+            // isn't one, the shorter distance is (probably) what happened.
+            let behind = count - ahead;
+            let forward = match along {
+                Some(along) => v.0.dot(along) > 0.0,
+                None => ahead < behind,
+            };
+            if !forward {
+                log::debug!("{entity:?} went back through checkpoint {n}");
+                car.progress = Some(progress - behind);
+                // Reversing (e.g. out of a crash) isn't going the wrong way
+                if v.0.dot(Vec2::from_angle(a.0)) > 0.0 {
+                    car.wrong_way = car.wrong_way.max(WRONG_WAY_TIME);
                 }
-                car.sub_count |= *bit;
-                if car.sub_count == mask && car.start_finish == *bit {
-                    car.lap_count += 1;
-                    car.sub_count = *bit;
+                continue;
+            }
+
+            if ahead > 1 {
+                infringements.send(penalty::Infringement {
+                    car: entity,
+                    rule: penalty::Rule::MissedCheckpoint,
+                    amount: (ahead - 1) as f32,
+                });
+            }
+            car.progress = Some(progress + ahead);
+            car.last_checkpoint = Some(respawn_point(xform, line.as_deref(), heading));
+
+            // Laps only count once (even if the car has been back over the
+            // line and come round again)
+            let now = time.elapsed_seconds();
+            let laps = (progress + ahead).div_euclid(count).max(0) as u32;
+            if laps > car.lap_count {
+                car.lap_count = laps;
+                car.times.finish_lap(now);
+                if car.lap_count == RACE_LAPS {
+                    car.finish_time = Some(now);
                 }
+            } else {
+                car.times.checkpoint(n as usize, now);
             }
         }
+
+        // Without a racing line the delta is only updated at the checkpoints
+        if let (Some(progress), Some(_)) = (car.progress, &line) {
+            let section = progress.rem_euclid(count) as usize;
+            let waypoint = car.waypoint;
+            car.times.follow(waypoint, section, time.elapsed_seconds());
+        }
+    }
+}
+
+/// Work out everyone's place in the race.
+///
+/// Cars that have finished are placed in the order they finished. Everyone
+/// else is placed by the number of checkpoints they have passed and then by
+/// how close they are to the next one.
+fn update_race_order(
+    mut cars: Query<(&mut Racer, &Transform)>,
+    checkpoints: Query<(&LapCounter, &Transform)>,
+) {
+    let mut centres = checkpoints
+        .iter()
+        .map(|(LapCounter(n), xform)| (*n, xform.translation.truncate()))
+        .collect::<Vec<_>>();
+    centres.sort_by_key(|(n, _)| *n);
+    let count = centres.len() as i32;
+
+    let mut order = cars
+        .iter_mut()
+        .map(|(car, xform)| {
+            let progress = car.progress.unwrap_or(i32::MIN);
+            let distance = match car.progress {
+                Some(progress) if count > 0 => {
+                    let (_, next) = centres[(progress + 1).rem_euclid(count) as usize];
+                    xform.translation.truncate().distance(next)
+                }
+                _ => f32::INFINITY,
+            };
+            (car, progress, distance)
+        })
+        .collect::<Vec<_>>();
+    order.sort_by(
+        |(a, pa, da), (b, pb, db)| match (a.finish_time, b.finish_time) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => pb.cmp(pa).then(da.total_cmp(db)),
+        },
+    );
+
+    for (n, (car, ..)) in order.iter_mut().enumerate() {
+        car.position = n + 1;
+    }
+}
+
+/// Find the centre of a checkpoint and the direction of the track through
+/// it. Without a racing line we have to trust the car's `heading`.
+fn respawn_point(
+    checkpoint: &Transform,
+    line: Option<&racing_line::RacingLine>,
+    heading: Vec2,
+) -> Transform {
+    let centre = checkpoint.translation.truncate();
+    let dir = line.map_or(heading, |line| line.direction(line.nearest(centre, 0)));
+    let angle = physics::Angle(dir.y.atan2(dir.x));
+    Transform::from_translation(centre.extend(0.0)).with_rotation(angle.to_quat())
+}
+
+/// Keep track of where each car is on the racing line and spot cars that
+/// are driving against it.
+///
+/// This is the only place the waypoint is updated, for every car, so everyone
+/// else can rely on it.
+fn detect_wrong_way(
+    mut cars: Query<(&Transform, &physics::Velocity, &physics::Angle, &mut Racer)>,
+    line: Option<Res<racing_line::RacingLine>>,
+    time: Res<Time>,
+) {
+    let Some(line) = line else {
+        return;
+    };
+
+    for (xform, v, a, mut car) in cars.iter_mut() {
+        car.waypoint = line.nearest(xform.translation.truncate(), car.waypoint);
+        let along = line.direction(car.waypoint);
+        let heading = Vec2::from_angle(a.0).dot(along);
+
+        if heading < 0.0 && v.0.dot(along) < -WRONG_WAY_SPEED {
+            car.wrong_way += time.delta_seconds();
+        } else if heading > 0.0 {
+            car.wrong_way = 0.0;
+        }
     }
 }
 
 fn track_player(
-    player: Query<(&Transform, &physics::Velocity, With<Player>)>,
+    player: Query<(&Transform, &Player)>,
     mut camera: Query<(&mut Transform, With<Camera>, Without<Player>)>,
     prefs: Res<Preferences>,
 ) {
-    for (txp, _, _) in player.iter() {
+    for (txp, _) in player.iter().filter(|(_, p)| p.is_lead()) {
         for (mut txc, _, _) in camera.iter_mut() {
             txc.translation.x = txp.translation.x;
             txc.translation.y = txp.translation.y;
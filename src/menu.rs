@@ -1,8 +1,13 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright (C) 2024 Daniel Thompson
 
-use super::{util::despawn_entities, GameState};
+use super::{
+    controls::{Action, Binding, Bindings},
+    util::despawn_entities,
+    GameState,
+};
 use bevy::{app::AppExit, prelude::*};
+use serde::{Deserialize, Serialize};
 
 const TEXT_COLOR: Color = Color::rgb(0.80, 0.95, 0.85);
 const BG_COLOR: Color = Color::rgb(0.0, 0.55, 0.32);
@@ -12,15 +17,79 @@ const HOVERED_BUTTON: Color = Color::rgb(0.20, 0.30, 0.20);
 const HOVERED_PRESSED_BUTTON: Color = Color::rgb(0.25, 0.65, 0.25);
 const PRESSED_BUTTON: Color = Color::rgb(0.35, 0.75, 0.35);
 
-// This plugin manages the menu, with 5 different screens:
+// This plugin manages the menu, with 7 different screens:
 // - a main menu with "New Game", "Settings", "Quit"
-// - a settings menu with two submenus and a back button
-// - two settings screen with a setting that can be set and a back button
+// - a settings menu with four submenus and a back button
+// - a game settings screen with the AI difficulty and a back button
+// - a display settings screen with several settings that can be set and a back button
+// - a sound settings screen with a setting that can be set and a back button
+// - a controls screen where each action can be rebound and a back button
 pub struct MenuPlugin;
 
 // One of the two settings that can be set through the menu. It will be a resource in the app
 #[derive(Resource, Debug, Component, PartialEq, Eq, Clone, Copy)]
-struct Volume(u32);
+pub struct Volume(pub u32);
+
+// The display settings. Like `Volume` these are all resources but they are applied to the
+// window and camera by `main.rs`
+#[derive(Resource, Debug, Component, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum DisplayMode {
+    Fullscreen,
+    Windowed,
+}
+
+#[derive(Resource, Debug, Component, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct Resolution(pub u32, pub u32);
+
+#[derive(Resource, Debug, Component, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct Vsync(pub bool);
+
+// Width of the visible part of the track, in world units
+#[derive(Resource, Debug, Component, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct ViewWidth(pub u32);
+
+// Which corner of the screen the minimap is drawn in (see `minimap.rs`)
+#[derive(Resource, Debug, Component, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum MinimapCorner {
+    Off,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+// How solid the minimap is, as a percentage
+#[derive(Resource, Debug, Component, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct MinimapOpacity(pub u32);
+
+// Whether the speed is also shown as a number under the speedometer
+#[derive(Resource, Debug, Component, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct DigitalSpeedo(pub bool);
+
+// The game settings. These are used by the AI drivers (see `ai.rs`)
+#[derive(Resource, Debug, Component, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl std::str::FromStr for Difficulty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "easy" => Ok(Difficulty::Easy),
+            "normal" => Ok(Difficulty::Normal),
+            "hard" => Ok(Difficulty::Hard),
+            _ => Err(format!("Unknown difficulty: {s}")),
+        }
+    }
+}
+
+// Whether the AI cars slow down when they are ahead (and speed up when they are behind)
+#[derive(Resource, Debug, Component, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct RubberBanding(pub bool);
 
 impl Plugin for MenuPlugin {
     fn build(&self, app: &mut App) {
@@ -31,6 +100,8 @@ impl Plugin for MenuPlugin {
             .add_state::<GameState>()
             .add_state::<MenuState>()
             .insert_resource(Volume(7))
+            .init_resource::<MenuFocus>()
+            .init_resource::<Rebinding>()
             .add_systems(OnEnter(GameState::Menu), menu_setup)
             // Systems to handle the main menu screen
             .add_systems(OnEnter(MenuState::Main), main_menu_setup)
@@ -44,6 +115,42 @@ impl Plugin for MenuPlugin {
                 OnExit(MenuState::Settings),
                 despawn_entities::<OnSettingsMenuScreen>,
             )
+            // Systems to handle the game settings screen
+            .add_systems(OnEnter(MenuState::SettingsGame), game_settings_menu_setup)
+            .add_systems(
+                Update,
+                (
+                    setting_button::<Difficulty>,
+                    setting_button::<RubberBanding>,
+                )
+                    .run_if(in_state(MenuState::SettingsGame)),
+            )
+            .add_systems(
+                OnExit(MenuState::SettingsGame),
+                despawn_entities::<OnGameSettingsMenuScreen>,
+            )
+            // Systems to handle the display settings screen
+            .add_systems(
+                OnEnter(MenuState::SettingsDisplay),
+                display_settings_menu_setup,
+            )
+            .add_systems(
+                Update,
+                (
+                    setting_button::<DisplayMode>,
+                    setting_button::<Resolution>,
+                    setting_button::<Vsync>,
+                    setting_button::<ViewWidth>,
+                    setting_button::<MinimapCorner>,
+                    setting_button::<MinimapOpacity>,
+                    setting_button::<DigitalSpeedo>,
+                )
+                    .run_if(in_state(MenuState::SettingsDisplay)),
+            )
+            .add_systems(
+                OnExit(MenuState::SettingsDisplay),
+                despawn_entities::<OnDisplaySettingsMenuScreen>,
+            )
             // Systems to handle the sound settings screen
             .add_systems(OnEnter(MenuState::SettingsSound), sound_settings_menu_setup)
             .add_systems(
@@ -54,10 +161,35 @@ impl Plugin for MenuPlugin {
                 OnExit(MenuState::SettingsSound),
                 despawn_entities::<OnSoundSettingsMenuScreen>,
             )
+            // Systems to handle the controls settings screen
+            .add_systems(
+                OnEnter(MenuState::SettingsControls),
+                controls_settings_menu_setup,
+            )
+            .add_systems(
+                Update,
+                (
+                    capture_binding,
+                    update_binding_labels.after(capture_binding),
+                )
+                    .run_if(in_state(MenuState::SettingsControls)),
+            )
+            .add_systems(
+                OnExit(MenuState::SettingsControls),
+                (
+                    despawn_entities::<OnControlsSettingsMenuScreen>,
+                    reset_rebinding,
+                ),
+            )
             // Common systems to all screens that handles buttons behavior
             .add_systems(
                 Update,
-                (menu_action, button_system).run_if(in_state(GameState::Menu)),
+                (
+                    menu_action,
+                    button_system,
+                    gamepad_navigation.after(button_system),
+                )
+                    .run_if(in_state(GameState::Menu)),
             );
     }
 }
@@ -67,7 +199,10 @@ impl Plugin for MenuPlugin {
 enum MenuState {
     Main,
     Settings,
+    SettingsGame,
+    SettingsDisplay,
     SettingsSound,
+    SettingsControls,
     #[default]
     Disabled,
 }
@@ -80,6 +215,10 @@ struct OnMainMenuScreen;
 #[derive(Component)]
 struct OnSettingsMenuScreen;
 
+// Tag component used to tag entities added on the game settings menu screen
+#[derive(Component)]
+struct OnGameSettingsMenuScreen;
+
 // Tag component used to tag entities added on the display settings menu screen
 #[derive(Component)]
 struct OnDisplaySettingsMenuScreen;
@@ -88,17 +227,49 @@ struct OnDisplaySettingsMenuScreen;
 #[derive(Component)]
 struct OnSoundSettingsMenuScreen;
 
+// Tag component used to tag entities added on the controls settings menu screen
+#[derive(Component)]
+struct OnControlsSettingsMenuScreen;
+
+// Tag component used to mark the text that shows the bindings for an action
+#[derive(Component)]
+struct BindingLabel(Action);
+
+// Tag component used to mark the text used to report binding conflicts
+#[derive(Component)]
+struct BindingNotice;
+
+// The action waiting for its next key or button press (if any) together with the
+// most recent message for the user
+#[derive(Resource, Default)]
+struct Rebinding {
+    action: Option<Action>,
+    notice: String,
+}
+
 // Tag component used to mark which setting is currently selected
 #[derive(Component)]
 struct SelectedOption;
 
+// Button currently focused by gamepad navigation (and a button whose press must be
+// released on the next frame)
+#[derive(Resource, Default)]
+struct MenuFocus {
+    focused: Option<Entity>,
+    pressed: Option<Entity>,
+}
+
 // All actions that can be triggered from a button click
 #[derive(Component)]
 enum MenuButtonAction {
     Resume,
     Play,
     Settings,
+    SettingsGame,
+    SettingsDisplay,
     SettingsSound,
+    SettingsControls,
+    Rebind(Action),
     BackToMainMenu,
     BackToSettings,
     Quit,
@@ -125,21 +296,123 @@ fn button_system(
 // the button as the one currently selected
 fn setting_button<T: Resource + Component + PartialEq + Copy>(
     interaction_query: Query<(&Interaction, &T, Entity), (Changed<Interaction>, With<Button>)>,
-    mut selected_query: Query<(Entity, &mut BackgroundColor), With<SelectedOption>>,
+    mut selected_query: Query<(Entity, &mut BackgroundColor), (With<SelectedOption>, With<T>)>,
     mut commands: Commands,
     mut setting: ResMut<T>,
 ) {
     for (interaction, button_setting, entity) in &interaction_query {
         if *interaction == Interaction::Pressed && *setting != *button_setting {
-            let (previous_button, mut previous_color) = selected_query.single_mut();
-            *previous_color = NORMAL_BUTTON.into();
-            commands.entity(previous_button).remove::<SelectedOption>();
+            // There can be several settings on the same screen (and the current value
+            // might not be one of the options) so we must only look at the buttons for
+            // this setting and cannot assume one is already selected
+            for (previous_button, mut previous_color) in selected_query.iter_mut() {
+                *previous_color = NORMAL_BUTTON.into();
+                commands.entity(previous_button).remove::<SelectedOption>();
+            }
             commands.entity(entity).insert(SelectedOption);
             *setting = *button_setting;
         }
     }
 }
 
+// This system lets the d-pad move the focus between buttons and the south button (A on
+// most pads) press the focused button
+fn gamepad_navigation(
+    gamepads: Res<Gamepads>,
+    input: Res<Input<GamepadButton>>,
+    mut focus: ResMut<MenuFocus>,
+    mut buttons: Query<
+        (
+            Entity,
+            &GlobalTransform,
+            &mut Interaction,
+            &mut BackgroundColor,
+            Option<&SelectedOption>,
+        ),
+        With<Button>,
+    >,
+) {
+    // Simulated presses last for a single frame
+    if let Some(entity) = focus.pressed.take() {
+        if let Ok((_, _, mut interaction, _, _)) = buttons.get_mut(entity) {
+            interaction.set_if_neq(Interaction::None);
+        }
+    }
+
+    let just_pressed = |button_type| {
+        gamepads
+            .iter()
+            .any(|pad| input.just_pressed(GamepadButton::new(pad, button_type)))
+    };
+    let direction = if just_pressed(GamepadButtonType::DPadUp) {
+        Vec2::NEG_Y
+    } else if just_pressed(GamepadButtonType::DPadDown) {
+        Vec2::Y
+    } else if just_pressed(GamepadButtonType::DPadLeft) {
+        Vec2::NEG_X
+    } else if just_pressed(GamepadButtonType::DPadRight) {
+        Vec2::X
+    } else {
+        Vec2::ZERO
+    };
+    let select = just_pressed(GamepadButtonType::South);
+    if direction == Vec2::ZERO && !select {
+        return;
+    }
+
+    // The previous focus may have been despawned when the menu screen changed
+    let current = focus
+        .focused
+        .and_then(|entity| buttons.get(entity).ok())
+        .map(|(entity, xform, _, _, _)| (entity, xform.translation().truncate()));
+
+    let next = match current {
+        // UI coordinates grow downwards so the top-left button is the smallest
+        None => buttons
+            .iter()
+            .map(|(entity, xform, _, _, _)| (entity, xform.translation().truncate()))
+            .min_by(|(_, a), (_, b)| a.y.total_cmp(&b.y).then(a.x.total_cmp(&b.x)))
+            .map(|(entity, _)| entity),
+        Some((entity, pos)) => buttons
+            .iter()
+            .map(|(entity, xform, _, _, _)| (entity, xform.translation().truncate() - pos))
+            .filter(|(_, offset)| offset.dot(direction) > 0.5)
+            // Prefer buttons that are in line with the direction of travel
+            .min_by(|(_, a), (_, b)| {
+                let cost = |v: &Vec2| v.length() + 2.0 * v.perp_dot(direction).abs();
+                cost(a).total_cmp(&cost(b))
+            })
+            .map(|(entity, _)| entity)
+            .or(Some(entity)),
+    };
+    let Some(next) = next else {
+        return;
+    };
+
+    if focus.focused != Some(next) {
+        if let Some(Ok((_, _, _, mut color, selected))) =
+            focus.focused.map(|entity| buttons.get_mut(entity))
+        {
+            *color = match selected {
+                Some(_) => PRESSED_BUTTON.into(),
+                None => NORMAL_BUTTON.into(),
+            };
+        }
+        if let Ok((_, _, _, mut color, selected)) = buttons.get_mut(next) {
+            *color = match selected {
+                Some(_) => HOVERED_PRESSED_BUTTON.into(),
+                None => HOVERED_BUTTON.into(),
+            };
+        }
+        focus.focused = Some(next);
+    } else if select {
+        if let Ok((_, _, mut interaction, _, _)) = buttons.get_mut(next) {
+            *interaction = Interaction::Pressed;
+            focus.pressed = Some(next);
+        }
+    }
+}
+
 fn menu_setup(mut menu_state: ResMut<NextState<MenuState>>) {
     menu_state.set(MenuState::Main);
 }
@@ -346,7 +619,10 @@ fn settings_menu_setup(mut commands: Commands) {
                 })
                 .with_children(|parent| {
                     for (action, text) in [
+                        (MenuButtonAction::SettingsGame, "Game"),
+                        (MenuButtonAction::SettingsDisplay, "Display"),
                         (MenuButtonAction::SettingsSound, "Sound"),
+                        (MenuButtonAction::SettingsControls, "Controls"),
                         (MenuButtonAction::BackToMainMenu, "Back"),
                     ] {
                         parent
@@ -369,6 +645,279 @@ fn settings_menu_setup(mut commands: Commands) {
         });
 }
 
+// Spawn a row with a label and one button for each option of a setting
+fn spawn_setting_row<T: Component + PartialEq + Copy>(
+    parent: &mut ChildBuilder,
+    label: &str,
+    options: &[(T, &str)],
+    current: T,
+) {
+    let button_style = Style {
+        height: Val::Px(65.0),
+        margin: UiRect::all(Val::Px(10.0)),
+        padding: UiRect::horizontal(Val::Px(10.0)),
+        justify_content: JustifyContent::Center,
+        align_items: AlignItems::Center,
+        ..default()
+    };
+    let label_text_style = TextStyle {
+        font_size: 40.0,
+        color: TEXT_COLOR,
+        ..default()
+    };
+    let button_text_style = TextStyle {
+        font_size: 24.0,
+        color: TEXT_COLOR,
+        ..default()
+    };
+
+    parent
+        .spawn(NodeBundle {
+            style: Style {
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            background_color: BG_COLOR.into(),
+            ..default()
+        })
+        .with_children(|parent| {
+            parent.spawn(
+                TextBundle::from_section(label, label_text_style).with_style(Style {
+                    width: Val::Px(250.0),
+                    margin: UiRect::all(Val::Px(20.0)),
+                    ..default()
+                }),
+            );
+            for (setting, text) in options {
+                let mut entity = parent.spawn((
+                    ButtonBundle {
+                        style: button_style.clone(),
+                        background_color: NORMAL_BUTTON.into(),
+                        ..default()
+                    },
+                    *setting,
+                ));
+                entity.with_children(|parent| {
+                    parent.spawn(TextBundle::from_section(*text, button_text_style.clone()));
+                });
+                if current == *setting {
+                    entity.insert(SelectedOption);
+                }
+            }
+        });
+}
+
+fn game_settings_menu_setup(
+    mut commands: Commands,
+    difficulty: Res<Difficulty>,
+    rubber_banding: Res<RubberBanding>,
+) {
+    let button_style = Style {
+        width: Val::Px(200.0),
+        height: Val::Px(65.0),
+        margin: UiRect::all(Val::Px(20.0)),
+        justify_content: JustifyContent::Center,
+        align_items: AlignItems::Center,
+        ..default()
+    };
+    let button_text_style = TextStyle {
+        font_size: 40.0,
+        color: TEXT_COLOR,
+        ..default()
+    };
+
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                },
+                ..default()
+            },
+            OnGameSettingsMenuScreen,
+        ))
+        .with_children(|parent| {
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Column,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    background_color: BG_COLOR.into(),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    spawn_setting_row(
+                        parent,
+                        "Difficulty",
+                        &[
+                            (Difficulty::Easy, "Easy"),
+                            (Difficulty::Normal, "Normal"),
+                            (Difficulty::Hard, "Hard"),
+                        ],
+                        *difficulty,
+                    );
+                    spawn_setting_row(
+                        parent,
+                        "Catch-up",
+                        &[(RubberBanding(true), "On"), (RubberBanding(false), "Off")],
+                        *rubber_banding,
+                    );
+                    parent
+                        .spawn((
+                            ButtonBundle {
+                                style: button_style,
+                                background_color: NORMAL_BUTTON.into(),
+                                ..default()
+                            },
+                            MenuButtonAction::BackToSettings,
+                        ))
+                        .with_children(|parent| {
+                            parent.spawn(TextBundle::from_section("Back", button_text_style));
+                        });
+                });
+        });
+}
+
+fn display_settings_menu_setup(
+    mut commands: Commands,
+    display_mode: Res<DisplayMode>,
+    resolution: Res<Resolution>,
+    vsync: Res<Vsync>,
+    view_width: Res<ViewWidth>,
+    (minimap_corner, minimap_opacity, digital_speedo): (
+        Res<MinimapCorner>,
+        Res<MinimapOpacity>,
+        Res<DigitalSpeedo>,
+    ),
+) {
+    let button_style = Style {
+        width: Val::Px(200.0),
+        height: Val::Px(65.0),
+        margin: UiRect::all(Val::Px(20.0)),
+        justify_content: JustifyContent::Center,
+        align_items: AlignItems::Center,
+        ..default()
+    };
+    let button_text_style = TextStyle {
+        font_size: 40.0,
+        color: TEXT_COLOR,
+        ..default()
+    };
+
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                },
+                ..default()
+            },
+            OnDisplaySettingsMenuScreen,
+        ))
+        .with_children(|parent| {
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Column,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    background_color: BG_COLOR.into(),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    spawn_setting_row(
+                        parent,
+                        "Mode",
+                        &[
+                            (DisplayMode::Fullscreen, "Fullscreen"),
+                            (DisplayMode::Windowed, "Window"),
+                        ],
+                        *display_mode,
+                    );
+                    spawn_setting_row(
+                        parent,
+                        "Resolution",
+                        &[
+                            (Resolution(1280, 720), "1280x720"),
+                            (Resolution(1600, 900), "1600x900"),
+                            (Resolution(1920, 1080), "1920x1080"),
+                            (Resolution(2560, 1440), "2560x1440"),
+                        ],
+                        *resolution,
+                    );
+                    spawn_setting_row(
+                        parent,
+                        "Vsync",
+                        &[(Vsync(true), "On"), (Vsync(false), "Off")],
+                        *vsync,
+                    );
+                    spawn_setting_row(
+                        parent,
+                        "View width",
+                        &[
+                            (ViewWidth(1280), "Near"),
+                            (ViewWidth(1920), "Normal"),
+                            (ViewWidth(2560), "Far"),
+                            (ViewWidth(3200), "Very far"),
+                        ],
+                        *view_width,
+                    );
+                    spawn_setting_row(
+                        parent,
+                        "Minimap",
+                        &[
+                            (MinimapCorner::Off, "Off"),
+                            (MinimapCorner::TopLeft, "Top left"),
+                            (MinimapCorner::TopRight, "Top right"),
+                            (MinimapCorner::BottomLeft, "Bottom left"),
+                            (MinimapCorner::BottomRight, "Bottom right"),
+                        ],
+                        *minimap_corner,
+                    );
+                    spawn_setting_row(
+                        parent,
+                        "Minimap opacity",
+                        &[
+                            (MinimapOpacity(25), "25%"),
+                            (MinimapOpacity(50), "50%"),
+                            (MinimapOpacity(75), "75%"),
+                            (MinimapOpacity(100), "100%"),
+                        ],
+                        *minimap_opacity,
+                    );
+                    spawn_setting_row(
+                        parent,
+                        "Digital speed",
+                        &[(DigitalSpeedo(true), "On"), (DigitalSpeedo(false), "Off")],
+                        *digital_speedo,
+                    );
+                    parent
+                        .spawn((
+                            ButtonBundle {
+                                style: button_style,
+                                background_color: NORMAL_BUTTON.into(),
+                                ..default()
+                            },
+                            MenuButtonAction::BackToSettings,
+                        ))
+                        .with_children(|parent| {
+                            parent.spawn(TextBundle::from_section("Back", button_text_style));
+                        });
+                });
+        });
+}
+
 fn sound_settings_menu_setup(mut commands: Commands, volume: Res<Volume>) {
     let button_style = Style {
         width: Val::Px(200.0),
@@ -458,6 +1007,225 @@ fn sound_settings_menu_setup(mut commands: Commands, volume: Res<Volume>) {
         });
 }
 
+// Describe all the bindings for an action
+fn binding_text(bindings: &Bindings, action: Action) -> String {
+    let text = bindings
+        .get(action)
+        .map(|binding| binding.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if text.is_empty() {
+        "Unbound".to_string()
+    } else {
+        text
+    }
+}
+
+fn controls_settings_menu_setup(
+    mut commands: Commands,
+    bindings: Res<Bindings>,
+    rebinding: Res<Rebinding>,
+) {
+    let button_style = Style {
+        width: Val::Px(200.0),
+        height: Val::Px(65.0),
+        margin: UiRect::all(Val::Px(20.0)),
+        justify_content: JustifyContent::Center,
+        align_items: AlignItems::Center,
+        ..default()
+    };
+    let button_text_style = TextStyle {
+        font_size: 40.0,
+        color: TEXT_COLOR,
+        ..default()
+    };
+    let binding_text_style = TextStyle {
+        font_size: 24.0,
+        color: TEXT_COLOR,
+        ..default()
+    };
+
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                },
+                ..default()
+            },
+            OnControlsSettingsMenuScreen,
+        ))
+        .with_children(|parent| {
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Column,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    background_color: BG_COLOR.into(),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    // One row for each action: the name of the action and a button
+                    // showing the current bindings (click to rebind)
+                    for action in Action::ALL {
+                        parent
+                            .spawn(NodeBundle {
+                                style: Style {
+                                    align_items: AlignItems::Center,
+                                    ..default()
+                                },
+                                background_color: BG_COLOR.into(),
+                                ..default()
+                            })
+                            .with_children(|parent| {
+                                parent.spawn(
+                                    TextBundle::from_section(
+                                        action.label(),
+                                        button_text_style.clone(),
+                                    )
+                                    .with_style(Style {
+                                        width: Val::Px(250.0),
+                                        margin: UiRect::all(Val::Px(20.0)),
+                                        ..default()
+                                    }),
+                                );
+                                parent
+                                    .spawn((
+                                        ButtonBundle {
+                                            style: Style {
+                                                width: Val::Px(400.0),
+                                                ..button_style.clone()
+                                            },
+                                            background_color: NORMAL_BUTTON.into(),
+                                            ..default()
+                                        },
+                                        MenuButtonAction::Rebind(action),
+                                    ))
+                                    .with_children(|parent| {
+                                        parent.spawn((
+                                            BindingLabel(action),
+                                            TextBundle::from_section(
+                                                binding_text(&bindings, action),
+                                                binding_text_style.clone(),
+                                            ),
+                                        ));
+                                    });
+                            });
+                    }
+                    parent.spawn((
+                        BindingNotice,
+                        TextBundle::from_section(
+                            notice_text(&rebinding, &bindings),
+                            binding_text_style.clone(),
+                        )
+                        .with_style(Style {
+                            margin: UiRect::all(Val::Px(10.0)),
+                            ..default()
+                        }),
+                    ));
+                    parent
+                        .spawn((
+                            ButtonBundle {
+                                style: button_style,
+                                background_color: NORMAL_BUTTON.into(),
+                                ..default()
+                            },
+                            MenuButtonAction::BackToSettings,
+                        ))
+                        .with_children(|parent| {
+                            parent.spawn(TextBundle::from_section("Back", button_text_style));
+                        });
+                });
+        });
+}
+
+// This system waits for the next key or gamepad button press after a rebind button has
+// been clicked and binds it to the action
+fn capture_binding(
+    mut rebinding: ResMut<Rebinding>,
+    mut bindings: ResMut<Bindings>,
+    keyboard: Res<Input<KeyCode>>,
+    buttons: Res<Input<GamepadButton>>,
+) {
+    let Some(action) = rebinding.action else {
+        return;
+    };
+    // Ignore the key or button press that started the rebind
+    if rebinding.is_changed() {
+        return;
+    }
+
+    let binding = keyboard
+        .get_just_pressed()
+        .next()
+        .map(|key| Binding::Key(*key))
+        .or_else(|| {
+            buttons
+                .get_just_pressed()
+                .next()
+                .map(|button| Binding::Button(button.button_type))
+        });
+    let Some(binding) = binding else {
+        return;
+    };
+
+    rebinding.action = None;
+    rebinding.notice = match bindings.rebind(action, binding) {
+        Some(other) if bindings.get(other).next().is_none() => format!(
+            "{binding} was taken from {} (which is now unbound)",
+            other.label()
+        ),
+        Some(other) => format!("{binding} was taken from {}", other.label()),
+        None => String::new(),
+    };
+}
+
+fn update_binding_labels(
+    rebinding: Res<Rebinding>,
+    bindings: Res<Bindings>,
+    mut labels: Query<(&mut Text, &BindingLabel)>,
+    mut notice: Query<&mut Text, (With<BindingNotice>, Without<BindingLabel>)>,
+) {
+    if !rebinding.is_changed() && !bindings.is_changed() {
+        return;
+    }
+
+    for (mut text, BindingLabel(action)) in labels.iter_mut() {
+        text.sections[0].value = if rebinding.action == Some(*action) {
+            "...".to_string()
+        } else {
+            binding_text(&bindings, *action)
+        };
+    }
+
+    for mut text in notice.iter_mut() {
+        text.sections[0].value = notice_text(&rebinding, &bindings);
+    }
+}
+
+// Combine the latest message with a warning for each conflicting binding
+fn notice_text(rebinding: &Rebinding, bindings: &Bindings) -> String {
+    let mut message = rebinding.notice.clone();
+    for (binding, a, b) in bindings.conflicts() {
+        message.push_str(&format!(
+            "\n{binding} is bound to both {} and {}",
+            a.label(),
+            b.label()
+        ));
+    }
+    message.trim_start().to_string()
+}
+
+fn reset_rebinding(mut rebinding: ResMut<Rebinding>) {
+    *rebinding = Rebinding::default();
+}
+
 fn menu_action(
     interaction_query: Query<
         (&Interaction, &MenuButtonAction),
@@ -466,6 +1234,7 @@ fn menu_action(
     mut app_exit_events: EventWriter<AppExit>,
     mut menu_state: ResMut<NextState<MenuState>>,
     mut game_state: ResMut<NextState<GameState>>,
+    mut rebinding: ResMut<Rebinding>,
 ) {
     for (interaction, menu_button_action) in &interaction_query {
         if *interaction == Interaction::Pressed {
@@ -480,9 +1249,20 @@ fn menu_action(
                     menu_state.set(MenuState::Disabled);
                 }
                 MenuButtonAction::Settings => menu_state.set(MenuState::Settings),
+                MenuButtonAction::SettingsGame => menu_state.set(MenuState::SettingsGame),
+                MenuButtonAction::SettingsDisplay => {
+                    menu_state.set(MenuState::SettingsDisplay);
+                }
                 MenuButtonAction::SettingsSound => {
                     menu_state.set(MenuState::SettingsSound);
                 }
+                MenuButtonAction::SettingsControls => {
+                    menu_state.set(MenuState::SettingsControls);
+                }
+                MenuButtonAction::Rebind(action) => {
+                    rebinding.action = Some(*action);
+                    rebinding.notice = format!("Press a key or button for {}", action.label());
+                }
                 MenuButtonAction::BackToMainMenu => menu_state.set(MenuState::Main),
                 MenuButtonAction::BackToSettings => {
                     menu_state.set(MenuState::Settings);
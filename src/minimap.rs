@@ -0,0 +1,326 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+//! A small map of the whole track, drawn in a corner of the dashboard.
+//!
+//! The outline is rasterised on the CPU from the track tiles when a level is
+//! loaded (in the same way as the [GuidanceField](crate::guidance)) and
+//! every racer and checkpoint is marked with a dot on top of it.
+
+#![allow(clippy::type_complexity)]
+
+use bevy::{
+    math::vec2,
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+};
+
+use crate::{
+    menu::{MinimapCorner, MinimapOpacity},
+    tilemap,
+    track::Track,
+    LapCounter, Player, Preferences, Racer,
+};
+
+/// Pixels in the minimap image for every tile of the track.
+const PIXELS_PER_TILE: usize = 4;
+
+const EDGE_COLOR: [u8; 4] = [240, 240, 240, 255];
+const SURFACE_COLOR: [u8; 4] = [40, 40, 40, 160];
+
+/// Gap between the minimap and the edge of the screen.
+const MARGIN: Val = Val::Px(24.0);
+
+#[derive(Default)]
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, spawn_minimap)
+            .add_systems(Update, (draw_track, place_minimap, update_markers).chain());
+    }
+}
+
+#[derive(Component, Debug)]
+struct Minimap;
+
+/// The node that the track is drawn in. The markers are its children so
+/// they turn with it in jenny mode.
+#[derive(Component, Debug)]
+struct MinimapTrack;
+
+/// A dot on the minimap following a racer or checkpoint.
+#[derive(Component, Debug)]
+struct Marker(Entity);
+
+/// How to convert world positions into positions on the minimap for the
+/// current level.
+#[derive(Resource, Debug)]
+struct Projection {
+    /// Size of a tile in world units
+    tile_size: Vec2,
+    /// Size of the map, in tiles
+    map_size: Vec2,
+    /// The length of each side of the (square) minimap, in tiles
+    side: f32,
+}
+
+impl Projection {
+    /// Find the position, as a percentage of the size of the minimap, from
+    /// the top left corner.
+    fn to_percent(&self, pos: Vec3) -> Vec2 {
+        let tile = pos.truncate() * vec2(1.0, -1.0) / self.tile_size + self.map_size / 2.0;
+        (tile + (self.side - self.map_size) / 2.0) * 100.0 / self.side
+    }
+}
+
+fn spawn_minimap(mut commands: Commands) {
+    commands
+        .spawn((
+            Name::new("Minimap"),
+            Minimap,
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    width: Val::VMax(100.0 * 256.0 / 1920.0),
+                    height: Val::VMax(100.0 * 256.0 / 1920.0),
+                    ..default()
+                },
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                MinimapTrack,
+                NodeBundle {
+                    style: Style {
+                        width: Val::Percent(100.0),
+                        height: Val::Percent(100.0),
+                        ..default()
+                    },
+                    background_color: Color::WHITE.into(),
+                    ..default()
+                },
+                UiImage::default(),
+            ));
+        });
+}
+
+/// Draw the track for each new level.
+fn draw_track(
+    mut commands: Commands,
+    mut map_events: EventReader<AssetEvent<tilemap::TiledMap>>,
+    maps: Res<Assets<tilemap::TiledMap>>,
+    mut images: ResMut<Assets<Image>>,
+    mut minimap: Query<&mut UiImage, With<MinimapTrack>>,
+) {
+    for event in map_events.read() {
+        let AssetEvent::Added { id } = event else {
+            continue;
+        };
+        let Some(map) = maps.get(*id) else {
+            continue;
+        };
+
+        let mut ui_image = minimap.single_mut();
+        ui_image.texture = images.add(rasterize(&map.track));
+        commands.insert_resource(Projection {
+            tile_size: vec2(map.map.tile_width as f32, map.map.tile_height as f32),
+            map_size: vec2(map.track.width as f32, map.track.height as f32),
+            side: map.track.width.max(map.track.height) as f32,
+        });
+    }
+}
+
+/// Draw the outline of the track, centred in a square image.
+fn rasterize(track: &Track) -> Image {
+    let side = track.width.max(track.height) * PIXELS_PER_TILE;
+    let left = (side - track.width * PIXELS_PER_TILE) / 2;
+    let top = (side - track.height * PIXELS_PER_TILE) / 2;
+    let mut data = vec![0; side * side * 4];
+
+    for y in 0..track.height as i32 {
+        for x in 0..track.width as i32 {
+            if !track.contains(x, y) {
+                continue;
+            }
+
+            // Tiles beside something that isn't track are on the edge
+            let last = PIXELS_PER_TILE - 1;
+            let open = |dx, dy| !track.contains(x + dx, y + dy);
+            for j in 0..PIXELS_PER_TILE {
+                for i in 0..PIXELS_PER_TILE {
+                    let edge = (i == 0 && open(-1, 0))
+                        || (i == last && open(1, 0))
+                        || (j == 0 && open(0, -1))
+                        || (j == last && open(0, 1));
+                    let px = left + x as usize * PIXELS_PER_TILE + i;
+                    let py = top + y as usize * PIXELS_PER_TILE + j;
+                    let offset = (py * side + px) * 4;
+                    data[offset..offset + 4].copy_from_slice(if edge {
+                        &EDGE_COLOR
+                    } else {
+                        &SURFACE_COLOR
+                    });
+                }
+            }
+        }
+    }
+
+    Image::new(
+        Extent3d {
+            width: side as u32,
+            height: side as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+    )
+}
+
+/// Move the minimap to the chosen corner, and hide it if it is turned off
+/// (or there is no track to show).
+fn place_minimap(
+    corner: Res<MinimapCorner>,
+    opacity: Res<MinimapOpacity>,
+    projection: Option<Res<Projection>>,
+    mut minimap: Query<(&mut Style, &mut Visibility), With<Minimap>>,
+    mut track: Query<&mut BackgroundColor, With<MinimapTrack>>,
+) {
+    let (mut style, mut visibility) = minimap.single_mut();
+
+    let shown = projection.is_some() && *corner != MinimapCorner::Off;
+    let new_visibility = if shown {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+    if *visibility != new_visibility {
+        *visibility = new_visibility;
+    }
+
+    if corner.is_changed() {
+        let (top, left) = match *corner {
+            MinimapCorner::Off | MinimapCorner::TopRight => (true, false),
+            MinimapCorner::TopLeft => (true, true),
+            MinimapCorner::BottomLeft => (false, true),
+            MinimapCorner::BottomRight => (false, false),
+        };
+        (style.top, style.bottom) = if top {
+            (MARGIN, Val::Auto)
+        } else {
+            (Val::Auto, MARGIN)
+        };
+        (style.left, style.right) = if left {
+            (MARGIN, Val::Auto)
+        } else {
+            (Val::Auto, MARGIN)
+        };
+    }
+
+    if opacity.is_changed() {
+        track.single_mut().0.set_a(opacity.0 as f32 / 100.0);
+    }
+}
+
+/// Keep a marker on the minimap for every racer and checkpoint.
+fn update_markers(
+    mut commands: Commands,
+    projection: Option<Res<Projection>>,
+    racers: Query<(Entity, &Transform, Option<&Player>), With<Racer>>,
+    checkpoints: Query<(Entity, &Transform, &LapCounter)>,
+    mut markers: Query<(Entity, &Marker, &mut Style)>,
+    mut track: Query<
+        (Entity, &mut Transform),
+        (With<MinimapTrack>, Without<Racer>, Without<LapCounter>),
+    >,
+    prefs: Res<Preferences>,
+) {
+    let Some(projection) = projection else {
+        return;
+    };
+    let (track, mut track_xform) = track.single_mut();
+
+    // Turn the map with the lead player so that it matches the view of the
+    // track in jenny mode
+    let rotation = racers
+        .iter()
+        .find(|(_, _, p)| p.is_some_and(|p| p.is_lead()))
+        .filter(|_| prefs.jenny_mode)
+        .map_or(Quat::IDENTITY, |(_, xform, _)| xform.rotation);
+    if track_xform.rotation != rotation {
+        track_xform.rotation = rotation;
+    }
+
+    let mut seen = Vec::new();
+    for (marker, target, mut style) in markers.iter_mut() {
+        let xform = racers
+            .get(target.0)
+            .map(|(_, xform, _)| xform)
+            .or_else(|_| checkpoints.get(target.0).map(|(_, xform, _)| xform));
+        let Ok(xform) = xform else {
+            commands.entity(marker).despawn_recursive();
+            continue;
+        };
+
+        let pos = projection.to_percent(xform.translation);
+        style.left = Val::Percent(pos.x);
+        style.top = Val::Percent(pos.y);
+        seen.push(target.0);
+    }
+
+    // Checkpoints are added first so the cars are drawn on top of them
+    let new_checkpoints =
+        checkpoints
+            .iter()
+            .filter(|(e, ..)| !seen.contains(e))
+            .map(|(e, xform, n)| {
+                let (size, color) = if n.0 == 0 {
+                    (8.0, Color::WHITE)
+                } else {
+                    (6.0, Color::rgb(1.0, 0.8, 0.0))
+                };
+                (e, xform, size, color)
+            });
+    let new_racers = racers
+        .iter()
+        .filter(|(e, ..)| !seen.contains(e))
+        .map(|(e, xform, p)| {
+            let (size, color) = match p {
+                Some(p) if p.is_lead() => (12.0, Color::rgb(0.9, 0.1, 0.1)),
+                Some(_) => (10.0, Color::rgb(0.1, 0.4, 0.9)),
+                None => (8.0, Color::rgb(0.7, 0.7, 0.7)),
+            };
+            (e, xform, size, color)
+        });
+
+    for (target, xform, size, color) in new_checkpoints.chain(new_racers) {
+        let pos = projection.to_percent(xform.translation);
+        let marker = commands
+            .spawn((
+                Marker(target),
+                NodeBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        left: Val::Percent(pos.x),
+                        top: Val::Percent(pos.y),
+                        width: Val::Px(size),
+                        height: Val::Px(size),
+                        // Centre the dot on the position
+                        margin: UiRect {
+                            left: Val::Px(-size / 2.0),
+                            top: Val::Px(-size / 2.0),
+                            ..default()
+                        },
+                        ..default()
+                    },
+                    background_color: color.into(),
+                    ..default()
+                },
+            ))
+            .id();
+        commands.entity(track).add_child(marker);
+    }
+}
@@ -10,14 +10,23 @@ use bevy::{
 };
 use std::f32::consts::PI;
 
-use crate::{geometry::Polygon, physics, tilemap, LapCounter, LevelComponent, Player, Racer};
+use crate::{
+    ai, controls, damage, geometry::Polygon, hazard, penalty, physics, racing_line::RacingLine,
+    respawn, tilemap, util, LapCounter, LevelComponent, Player, Racer,
+};
 
 #[derive(Default)]
 pub struct Plugin;
 
 impl bevy::app::Plugin for Plugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, (handle_map_events,));
+        app.add_systems(
+            Update,
+            (
+                handle_map_events,
+                knock_over_scenery.after(physics::collision_detection),
+            ),
+        );
     }
 }
 
@@ -33,7 +42,11 @@ pub fn handle_map_events(
         match event {
             AssetEvent::Added { id } => {
                 if let Some(map) = maps.get(*id) {
-                    spawn_objects(&map.map, &mut commands, &mut texture_atlas, &asset_server);
+                    spawn_objects(&map.map, &mut commands, &mut |img| {
+                        load_atlas(img, &mut texture_atlas, &asset_server)
+                    });
+                    commands.insert_resource(level_difficulty(&map.map));
+                    commands.insert_resource(penalty::PenaltyRules::from_map(&map.map));
                 }
             }
             _ => continue,
@@ -41,24 +54,41 @@ pub fn handle_map_events(
     }
 }
 
+/// Levels can force the AI to a particular difficulty with a `difficulty`
+/// property (`easy`, `normal` or `hard`).
+fn level_difficulty(map: &tiled::Map) -> ai::LevelDifficulty {
+    let difficulty = match map.properties.get("difficulty") {
+        Some(tiled::PropertyValue::StringValue(s)) => s.parse().map_err(|e| error!("{e}")).ok(),
+        _ => None,
+    };
+    ai::LevelDifficulty(difficulty)
+}
+
 /// Grub about in the bowels of the tiled data, iterating over each
 /// object and trying to figure out what sprite to create.
 ///
 /// Once we finish wading through the tiled data we call out to
-/// `spawn_object()` to do the bevy actions!
-fn spawn_objects(
+/// `spawn_object()` to do the bevy actions! `atlas_for` provides the sprite
+/// for each tile image.
+pub fn spawn_objects(
     map: &tiled::Map,
     commands: &mut Commands,
-    texture_atlas: &mut Assets<TextureAtlas>,
-    asset_server: &AssetServer,
+    atlas_for: &mut impl FnMut(&tiled::Image) -> Handle<TextureAtlas>,
 ) {
-    let mut shape_number = 0;
+    let mut checkpoints = Vec::new();
 
     for layer in map.layers().filter_map(|layer| layer.as_object_layer()) {
         for obj in layer.objects() {
             let Some(tile_data) = obj.tile_data() else {
-                spawn_shape(map, &obj, shape_number, commands);
-                shape_number += 1;
+                if obj.name == "ai_line" {
+                    spawn_ai_line(map, &obj, commands);
+                } else if is_hazard_zone(&obj) {
+                    spawn_hazard_zone(map, &obj, commands);
+                } else if matches!(obj.shape, tiled::ObjectShape::Rect { .. }) {
+                    checkpoints.push(obj);
+                } else {
+                    error!("Unsupported shape: {:?}", (&obj.name, &obj.shape));
+                }
                 continue;
             };
 
@@ -78,114 +108,352 @@ fn spawn_objects(
                 continue;
             };
 
-            spawn_object(map, &obj, image, commands, texture_atlas, asset_server);
+            spawn_object(map, &obj, image, &tile, commands, atlas_for);
         }
     }
+
+    checkpoints.sort_by(|a, b| checkpoint_order(a).total_cmp(&checkpoint_order(b)));
+    for (n, obj) in checkpoints.iter().enumerate() {
+        spawn_checkpoint(map, obj, n, commands);
+    }
 }
 
 fn spawn_object(
     map: &tiled::Map,
     obj: &tiled::Object,
     img: &tiled::Image,
+    tile: &tiled::Tile,
     commands: &mut Commands,
-    texture_atlas: &mut Assets<TextureAtlas>,
-    asset_server: &AssetServer,
+    atlas_for: &mut impl FnMut(&tiled::Image) -> Handle<TextureAtlas>,
 ) {
     let Some(img_src) = img.source.to_str() else {
         error!("Cannot convert image name");
         return;
     };
-    let is_car = img_src.contains("car");
-    let is_player = is_car && img_src.contains("red");
+    let is_car = is_car(img_src);
+    let is_player = is_player(img_src);
+    let is_hazard = is_hazard(img_src);
 
     let sz = vec2(img.width as f32, img.height as f32);
-    let polygon = if img_src.contains("tree") {
-        Polygon::from_vec_with_rounding(&(sz * 0.5), 40.)
-    } else if img_src.contains("tires") {
-        Polygon::from_vec_with_rounding(&sz, 40.)
-    } else if img_src.contains("car") {
-        Polygon::from_vec_with_rounding(&sz, 60.)
+    let polygon = collision_polygon(img_src, sz);
+
+    let mut transform = tile_transform(map, obj, sz);
+    transform.translation.z = if is_hazard {
+        1.5
+    } else if is_car {
+        2.0
     } else {
-        Polygon::from_vec(&sz)
+        5.0
     };
 
-    let translation = vec3(
-        obj.x - (((map.width * map.tile_width) as f32 - img.width as f32) / 2.0),
-        -obj.y + (((map.height * map.tile_height) as f32 + img.height as f32) / 2.0),
-        if is_car { 2.0 } else { 5.0 },
-    );
-    let rotation = Quat::from_rotation_z(-obj.rotation * PI / 4.0);
-
-    // tiled rotates objects from the bottom-left but bevy rotates objects
-    // from the centre. that means we need to fix up the translation.
-    let shift = Vec3::from((sz / 2.0, 0.0));
-    let restore = rotation.mul_vec3(shift);
-
-    let mut path = std::path::PathBuf::from("embedded://");
-    path.push(&img.source);
-
-    let handle = asset_server.load(path.to_str().expect("tile_path is not UTF-8").to_string());
     let mut entity = commands.spawn((
         LevelComponent,
-        physics::CollisionBox(polygon),
         SpriteSheetBundle {
-            texture_atlas: texture_atlas.add(TextureAtlas::from_grid(handle, sz, 1, 1, None, None)),
-            transform: Transform {
-                translation: translation - shift + restore,
-                rotation,
-                scale: Vec3::ONE,
-            },
+            texture_atlas: atlas_for(img),
+            transform,
             ..default()
         },
     ));
 
+    if is_hazard {
+        // Hazards are driven over rather than into so, like the lap
+        // counters, they get a shape box rather than a collision box.
+        let sz = match obj.shape {
+            tiled::ObjectShape::Rect { width, height } => vec2(width, height),
+            _ => sz,
+        };
+        entity.insert((
+            Name::new("Hazard"),
+            hazard::Hazard {
+                radius: sz.max_element() / 2.0,
+            },
+            physics::ShapeBox(Polygon::from_vec_with_rounding(&sz, 40.)),
+        ));
+        return;
+    }
+
+    entity.insert(physics::CollisionBox(polygon));
     if is_car {
+        // Cars that get lost before reaching the first checkpoint go back to
+        // the grid
+        let angle = physics::Angle((90.0 - obj.rotation) * PI / 4.0);
         entity.insert((
-            Racer::default(),
-            physics::Angle((90.0 - obj.rotation) * PI / 4.0),
+            Racer {
+                last_checkpoint: Some(transform.with_rotation(angle.to_quat())),
+                ..default()
+            },
+            angle,
+            physics::Engine::default(),
             physics::Velocity(Vec2::new(0.0, 0.0)),
+            respawn::Recovery::default(),
         ));
 
+        // Damage is on by default but designers can switch it off for a level
+        if util::bool_property(&map.properties, "damage").unwrap_or(true) {
+            entity.insert(damage::Damage::default());
+        }
+
         if is_player {
-            entity.insert((Name::new("Human"), Player));
+            entity.insert((Name::new("Human"), Player(0), controls::Controls::default()));
         } else {
-            entity.insert(Name::new("AI"));
+            // Name the AI after the colour of the car (so they can be told
+            // apart in the results)
+            let colour = img_src
+                .rsplit('/')
+                .next()
+                .and_then(|f| f.split('_').nth(1))
+                .unwrap_or("car");
+            entity.insert(Name::new(format!("AI ({colour})")));
         }
     } else {
-        entity.insert(Name::new("Scenery"));
+        let float_property = |name| {
+            util::float_property(&obj.properties, name)
+                .or_else(|| util::float_property(&tile.properties, name))
+        };
+        let hardness = float_property("hardness")
+            .map(|h| damage::Hardness(h.clamp(0.0, 1.0)))
+            .unwrap_or_else(|| damage::Hardness::from_image(img_src));
+        entity.insert((Name::new("Scenery"), hardness));
+
+        // Dynamic scenery moves when it is hit (and, if there is a fallen
+        // version of the sprite, gets knocked over too)
+        let dynamic = util::bool_property(&obj.properties, "dynamic")
+            .or_else(|| util::bool_property(&tile.properties, "dynamic"))
+            .unwrap_or(false);
+        if dynamic {
+            let mass = float_property("mass").unwrap_or(0.2).max(0.01);
+            entity.insert((physics::Velocity(Vec2::ZERO), physics::Mass(mass)));
+
+            if let Some(down) = find_fallen_image(tile.tileset(), img_src) {
+                let down_sz = vec2(down.width as f32, down.height as f32);
+                entity.insert(KnockOver {
+                    texture_atlas: atlas_for(&down),
+                    polygon: collision_polygon(img_src, down_sz),
+                });
+            }
+        }
+    }
+}
+
+/// A piece of dynamic scenery that is still standing up.
+#[derive(Component, Debug)]
+struct KnockOver {
+    texture_atlas: Handle<TextureAtlas>,
+    polygon: Polygon,
+}
+
+/// Scenery that is knocked harder than this (i.e. its change of velocity is
+/// greater) falls down, if it can.
+const KNOCK_OVER_IMPULSE: f32 = 150.0;
+
+/// Tile objects are recognised by the name of their image.
+pub fn is_car(img_src: &str) -> bool {
+    img_src.contains("car")
+}
+
+/// The red car is driven by the (lead) player.
+pub fn is_player(img_src: &str) -> bool {
+    is_car(img_src) && img_src.contains("red")
+}
+
+pub fn is_hazard(img_src: &str) -> bool {
+    img_src.contains("oil")
+}
+
+/// Work out where a tile object is.
+///
+/// tiled rotates tile objects around their bottom-left corner but bevy
+/// rotates objects around their centre. that means we need to fix up the
+/// translation.
+pub fn tile_transform(map: &tiled::Map, obj: &tiled::Object, sz: Vec2) -> Transform {
+    let translation = vec3(
+        obj.x - (((map.width * map.tile_width) as f32 - sz.x) / 2.0),
+        -obj.y + (((map.height * map.tile_height) as f32 + sz.y) / 2.0),
+        0.0,
+    );
+    let rotation = Quat::from_rotation_z(-obj.rotation * PI / 4.0);
+    let shift = Vec3::from((sz / 2.0, 0.0));
+    let restore = rotation.mul_vec3(shift);
+    Transform {
+        translation: translation - shift + restore,
+        rotation,
+        scale: Vec3::ONE,
+    }
+}
+
+pub fn collision_polygon(img_src: &str, sz: Vec2) -> Polygon {
+    if img_src.contains("tree") {
+        Polygon::from_vec_with_rounding(&(sz * 0.5), 40.)
+    } else if img_src.contains("tires") {
+        Polygon::from_vec_with_rounding(&sz, 40.)
+    } else if img_src.contains("car") {
+        Polygon::from_vec_with_rounding(&sz, 60.)
+    } else {
+        Polygon::from_vec(&sz)
+    }
+}
+
+fn load_atlas(
+    img: &tiled::Image,
+    texture_atlas: &mut Assets<TextureAtlas>,
+    asset_server: &AssetServer,
+) -> Handle<TextureAtlas> {
+    let mut path = std::path::PathBuf::from("embedded://");
+    path.push(&img.source);
+
+    let sz = vec2(img.width as f32, img.height as f32);
+    let handle = asset_server.load(path.to_str().expect("tile_path is not UTF-8").to_string());
+    texture_atlas.add(TextureAtlas::from_grid(handle, sz, 1, 1, None, None))
+}
+
+/// Look for the fallen version of a sprite in the same tileset.
+///
+/// The racing pack names them consistently: `barrel_red.png` falls over to
+/// become `barrel_red_down.png` and `cone_straight.png` becomes
+/// `cone_down.png`.
+fn find_fallen_image(tileset: &tiled::Tileset, img_src: &str) -> Option<tiled::Image> {
+    let stem = img_src.strip_suffix(".png")?;
+    let stem = stem.strip_suffix("_straight").unwrap_or(stem);
+    let down = format!("{stem}_down.png");
+
+    tileset
+        .tiles()
+        .filter_map(|(_, tile)| tile.image.clone())
+        .find(|img| img.source.to_str() == Some(down.as_str()))
+}
+
+/// Swap standing scenery for its fallen version when it is hit hard enough.
+fn knock_over_scenery(
+    mut commands: Commands,
+    mut collisions: EventReader<physics::CarCarCollision>,
+    mut scenery: Query<(
+        &KnockOver,
+        &mut Handle<TextureAtlas>,
+        &mut physics::CollisionBox,
+    )>,
+) {
+    for e in collisions.read() {
+        for (entity, impulse) in [(e.a, e.impulse_a), (e.b, e.impulse_b)] {
+            if impulse < KNOCK_OVER_IMPULSE {
+                continue;
+            }
+            if let Ok((knock_over, mut atlas, mut collision_box)) = scenery.get_mut(entity) {
+                *atlas = knock_over.texture_atlas.clone();
+                collision_box.0 = knock_over.polygon.clone();
+                commands.entity(entity).remove::<KnockOver>();
+            }
+        }
     }
 }
 
-fn spawn_shape(map: &tiled::Map, obj: &tiled::Object, num: u32, commands: &mut Commands) {
-    match obj.shape {
+/// Work out where a shape object is.
+///
+/// tiled positions shapes from their top-left corner and rotates them around
+/// that corner too.
+pub fn shape_transform(map: &tiled::Map, obj: &tiled::Object, sz: Vec2) -> Transform {
+    let translation = vec3(
+        obj.x - (((map.width * map.tile_width) as f32 - sz.x) / 2.0),
+        -obj.y + (((map.height * map.tile_height) as f32 + sz.y) / 2.0) - sz.y,
+        0.0,
+    );
+    let rotation = Quat::from_rotation_z(-obj.rotation * PI / 4.0);
+    let shift = Vec3::from((sz / 2.0, 0.0));
+    let restore = rotation.mul_vec3(shift);
+    Transform {
+        translation: translation - shift + restore,
+        rotation,
+        scale: Vec3::ONE,
+    }
+}
+
+/// Checkpoints must be driven in order.
+///
+/// Designers number the checkpoints using an `order` property (the lowest is
+/// the start/finish line). Any without one are taken in the order they were
+/// drawn, after those that have one.
+pub fn checkpoint_order(obj: &tiled::Object) -> f32 {
+    util::float_property(&obj.properties, "order").unwrap_or(f32::INFINITY)
+}
+
+fn spawn_checkpoint(map: &tiled::Map, obj: &tiled::Object, num: usize, commands: &mut Commands) {
+    let tiled::ObjectShape::Rect { width, height } = obj.shape else {
+        return;
+    };
+    let sz = vec2(width, height);
+    let bbox = Polygon::from_vec(&sz);
+
+    commands.spawn((
+        Name::new("Checkpoint"),
+        LapCounter(num),
+        LevelComponent,
+        physics::ShapeBox(bbox),
+        shape_transform(map, obj, sz),
+    ));
+}
+
+/// Invisible hazards are rectangles or ellipses with the `hazard` class.
+pub fn is_hazard_zone(obj: &tiled::Object) -> bool {
+    obj.user_type == "hazard"
+}
+
+fn spawn_hazard_zone(map: &tiled::Map, obj: &tiled::Object, commands: &mut Commands) {
+    let (sz, bbox) = match obj.shape {
         tiled::ObjectShape::Rect { width, height } => {
             let sz = vec2(width, height);
-            let bbox = Polygon::from_vec(&sz);
-
-            let translation = vec3(
-                obj.x - (((map.width * map.tile_width) as f32 - width as f32) / 2.0),
-                -obj.y + (((map.height * map.tile_height) as f32 + height as f32) / 2.0) - height,
-                0.0,
-            );
-            let rotation = Quat::from_rotation_z(-obj.rotation * PI / 4.0);
-            let shift = Vec3::from((sz / 2.0, 0.0));
-            let restore = rotation.mul_vec3(shift);
-            let transform = Transform {
-                translation: translation - shift + restore,
-                rotation,
-                scale: Vec3::ONE,
-            };
-
-            commands.spawn((
-                Name::new("Checkpoint"),
-                LapCounter(1 << num),
-                LevelComponent,
-                physics::ShapeBox(bbox),
-                transform,
-            ));
+            (sz, Polygon::from_vec(&sz))
         }
+        tiled::ObjectShape::Ellipse { width, height } => {
+            let sz = vec2(width, height);
+            (sz, Polygon::from_vec_with_rounding(&sz, 40.))
+        }
+        _ => {
+            error!("Unsupported hazard shape: {:?}", (&obj.name, &obj.shape));
+            return;
+        }
+    };
+
+    commands.spawn((
+        Name::new("Hazard"),
+        hazard::Hazard {
+            radius: sz.max_element() / 2.0,
+        },
+        LevelComponent,
+        physics::ShapeBox(bbox),
+        shape_transform(map, obj, sz),
+    ));
+}
+
+/// Convert an `ai_line` polyline (or polygon) into the racing line.
+///
+/// A target speed can be set for any vertex using a property named after
+/// the vertex number (`speed.0` is the first vertex).
+fn spawn_ai_line(map: &tiled::Map, obj: &tiled::Object, commands: &mut Commands) {
+    let points = match &obj.shape {
+        tiled::ObjectShape::Polyline { points } | tiled::ObjectShape::Polygon { points } => points,
         _ => {
-            error!("Unsupported shape: {:?}", (&obj.name, &obj.shape));
+            error!("ai_line must be a polyline: {:?}", &obj.shape);
+            return;
+        }
+    };
+
+    let origin = vec2(
+        obj.x - (map.width * map.tile_width) as f32 / 2.0,
+        -obj.y + (map.height * map.tile_height) as f32 / 2.0,
+    );
+    let rotation = Vec2::from_angle(-obj.rotation.to_radians());
+    let path = points
+        .iter()
+        .map(|&(x, y)| origin + rotation.rotate(vec2(x, -y)))
+        .collect::<Vec<_>>();
+    let speeds = (0..path.len())
+        .map(|n| util::float_property(&obj.properties, &format!("speed.{n}")))
+        .collect::<Vec<_>>();
+
+    match RacingLine::from_path(&path, &speeds) {
+        Some(line) => {
+            log::info!("Using designer racing line with {} vertices", path.len());
+            commands.insert_resource(line);
         }
+        None => error!("ai_line is too short"),
     }
 }
@@ -0,0 +1,215 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+#![allow(clippy::type_complexity)]
+
+use bevy::{log, math::vec2, prelude::*};
+use itertools::Itertools;
+use std::fmt;
+
+use crate::{tilemap, util, Racer};
+
+/// Distance (in world units) the centre of a car can stray from the track
+/// without being treated as off the track (roughly speaking, while a wheel is
+/// still on the track).
+const TRACK_MARGIN: f32 = 40.0;
+
+#[derive(Default)]
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PenaltyRules>()
+            .add_event::<Infringement>()
+            .add_systems(
+                Update,
+                (
+                    check_track_limits,
+                    apply_penalties.after(check_track_limits),
+                ),
+            );
+    }
+}
+
+/// The rules of the race.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rule {
+    /// Leaving the track and rejoining it further on without going far from
+    /// the edge of the track (e.g. clipping the corner of a tile).
+    TileSkip,
+    /// Driving across the grass and rejoining the track further on.
+    GrassCut,
+    /// Crossing the start/finish line without passing every checkpoint.
+    MissedCheckpoint,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rule::TileSkip => write!(f, "skipping tiles"),
+            Rule::GrassCut => write!(f, "cutting across the grass"),
+            Rule::MissedCheckpoint => write!(f, "missing a checkpoint"),
+        }
+    }
+}
+
+/// Severity of each rule, in seconds of penalty per tile skipped (or per
+/// checkpoint missed). A severity of zero turns the rule off.
+#[derive(Resource, Clone, Debug)]
+pub struct PenaltyRules {
+    pub tile_skip: f32,
+    pub grass_cut: f32,
+    pub missed_checkpoint: f32,
+}
+
+impl Default for PenaltyRules {
+    fn default() -> Self {
+        Self {
+            tile_skip: 1.0,
+            grass_cut: 2.0,
+            missed_checkpoint: 5.0,
+        }
+    }
+}
+
+impl PenaltyRules {
+    /// Levels can change the severities using the `penalty.tile_skip`,
+    /// `penalty.grass_cut` and `penalty.missed_checkpoint` properties.
+    pub fn from_map(map: &tiled::Map) -> Self {
+        let default = Self::default();
+        let severity = |name: &str, default: f32| {
+            util::float_property(&map.properties, &format!("penalty.{name}"))
+                .map_or(default, |s| s.max(0.0))
+        };
+
+        Self {
+            tile_skip: severity("tile_skip", default.tile_skip),
+            grass_cut: severity("grass_cut", default.grass_cut),
+            missed_checkpoint: severity("missed_checkpoint", default.missed_checkpoint),
+        }
+    }
+
+    pub fn severity(&self, rule: Rule) -> f32 {
+        match rule {
+            Rule::TileSkip => self.tile_skip,
+            Rule::GrassCut => self.grass_cut,
+            Rule::MissedCheckpoint => self.missed_checkpoint,
+        }
+    }
+}
+
+/// A racer has broken one of the rules. The amount is the number of tiles
+/// skipped (or checkpoints missed).
+#[derive(Event, Clone, Debug)]
+pub struct Infringement {
+    pub car: Entity,
+    pub rule: Rule,
+    pub amount: f32,
+}
+
+/// A penalty handed out to a racer, kept for the race results.
+#[derive(Clone, Debug)]
+pub struct Penalty {
+    pub lap: u32,
+    pub rule: Rule,
+    pub seconds: f32,
+}
+
+impl fmt::Display for Penalty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "+{:.1}s for {} on lap {}",
+            self.seconds, self.rule, self.lap
+        )
+    }
+}
+
+/// Track whether each racer has left the track and rejoined it more than one
+/// tile further on.
+///
+/// If the car went out onto the grass (i.e. well away from the edge of the
+/// track) this is a grass cut, otherwise it is a tile skip.
+fn check_track_limits(
+    mut query: Query<(Entity, &Transform, &mut Racer)>,
+    maps: Res<Assets<tilemap::TiledMap>>,
+    mut events: EventWriter<Infringement>,
+) {
+    let (map, track) = match maps.iter().next() {
+        Some((_, map)) => (&map.map, &map.track),
+        None => return,
+    };
+    let on_track = |x: i32, y: i32| track.contains(x, y);
+    let margin = vec2(
+        TRACK_MARGIN / map.tile_width as f32,
+        TRACK_MARGIN / map.tile_height as f32,
+    );
+
+    for (car, t, mut r) in query.iter_mut() {
+        let x = (t.translation.x / map.tile_width as f32) + (map.width as f32 / 2.0);
+        let y = (-t.translation.y / map.tile_height as f32) + (map.height as f32 / 2.0);
+        let (tx, ty) = (x as i32, y as i32);
+
+        let near_track = [
+            vec2(0.0, 0.0),
+            vec2(margin.x, 0.0),
+            vec2(-margin.x, 0.0),
+            vec2(0.0, margin.y),
+            vec2(0.0, -margin.y),
+        ]
+        .iter()
+        .any(|d| on_track((x + d.x) as i32, (y + d.y) as i32));
+        if !near_track {
+            if !(-1..=1)
+                .cartesian_product(-1..=1)
+                .any(|(dx, dy)| on_track(tx + dx, ty + dy))
+            {
+                r.on_grass = true;
+            }
+            continue;
+        }
+
+        let now = vec2(x, y);
+        if let Some(prev) = r.last_tile {
+            let distance = now.distance(prev);
+            if distance > 1.0 {
+                events.send(Infringement {
+                    car,
+                    rule: if r.on_grass {
+                        Rule::GrassCut
+                    } else {
+                        Rule::TileSkip
+                    },
+                    amount: distance,
+                });
+            }
+        }
+        r.last_tile = Some(now);
+        r.on_grass = false;
+    }
+}
+
+fn apply_penalties(
+    mut events: EventReader<Infringement>,
+    mut racers: Query<&mut Racer>,
+    rules: Res<PenaltyRules>,
+) {
+    for Infringement { car, rule, amount } in events.read() {
+        let seconds = rules.severity(*rule) * amount;
+        if seconds <= 0.0 {
+            continue;
+        }
+        let Ok(mut r) = racers.get_mut(*car) else {
+            continue;
+        };
+
+        log::info!("{car:?} penalised {seconds:.1}s for {rule}");
+        r.penalty += seconds;
+        let lap = r.lap_count;
+        r.penalties.push(Penalty {
+            lap,
+            rule: *rule,
+            seconds,
+        });
+    }
+}
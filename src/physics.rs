@@ -3,11 +3,11 @@
 
 #![allow(clippy::type_complexity)]
 
-use bevy::{math::vec2, prelude::*};
+use bevy::{log, math::vec2, prelude::*};
 use slicetools::*;
 use std::f32::consts::PI;
 
-use crate::{geometry::*, mapping, Preferences};
+use crate::{geometry::*, guidance, Preferences, Racer};
 
 #[derive(Component, Debug, Reflect)]
 pub struct Velocity(pub Vec2);
@@ -18,6 +18,82 @@ pub struct Angle(pub f32);
 #[derive(Component, Clone, Debug)]
 pub struct CollisionBox(pub Polygon);
 
+/// A car that doesn't collide with anything (e.g. because it has just been
+/// put back on the track). The value is the time left, in seconds.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Ghost(pub f32);
+
+/// Mass of a moving object, relative to a car.
+///
+/// Anything with a [Velocity] but no mass is assumed to weigh the same as a
+/// car.
+#[derive(Component, Clone, Copy, Debug, Reflect)]
+pub struct Mass(pub f32);
+
+/// A car has collided with another car or with some pushable scenery.
+///
+/// Pushable scenery bumping into other scenery isn't reported.
+///
+/// The normal points from `a` towards `b` and the impulses are the changes
+/// of velocity applied to each of them (which differ if one is lighter than
+/// the other).
+#[derive(Event, Clone, Debug)]
+pub struct CarCarCollision {
+    pub a: Entity,
+    pub b: Entity,
+    pub point: Vec2,
+    pub normal: Vec2,
+    pub impulse_a: f32,
+    pub impulse_b: f32,
+}
+
+/// A car has collided with a piece of immovable scenery.
+///
+/// As for [CarCarCollision], pushable scenery hitting immovable scenery isn't
+/// reported.
+///
+/// The normal points away from the scenery (i.e. it is the direction the car
+/// was pushed in) and the impulse is the change of velocity of the car.
+#[derive(Event, Clone, Debug)]
+pub struct CarSceneryCollision {
+    pub car: Entity,
+    pub scenery: Entity,
+    pub point: Vec2,
+    pub normal: Vec2,
+    pub impulse: f32,
+}
+
+/// A (very) simple model of an engine and gearbox.
+///
+/// The physics doesn't need this at all; the car is simply pushed along.
+/// However the engine speed and load are needed to make the car sound
+/// right.
+#[derive(Component, Debug)]
+pub struct Engine {
+    pub rpm: f32,
+    pub gear: usize,
+    pub load: f32,
+    forward_speed: f32,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self {
+            rpm: IDLE_RPM,
+            gear: 0,
+            load: 0.0,
+            forward_speed: 0.0,
+        }
+    }
+}
+
+const IDLE_RPM: f32 = 900.0;
+const SHIFT_UP_RPM: f32 = 6500.0;
+const SHIFT_DOWN_RPM: f32 = 3000.0;
+
+/// RPM per unit of speed (in world units per second) for each gear.
+const GEAR_RATIOS: [f32; 5] = [40.0, 24.0, 16.0, 12.0, 10.0];
+
 #[derive(Component, Clone, Debug)]
 pub struct ShapeBox(pub Polygon);
 
@@ -34,12 +110,18 @@ impl Angle {
     pub fn to_quat(&self) -> Quat {
         Quat::from_rotation_z(self.0 - PI / 2.0)
     }
+
+    pub fn from_quat(quat: Quat) -> Self {
+        let mut angle = Self(quat.to_euler(EulerRot::ZYX).0 + PI / 2.0);
+        angle.normalize();
+        angle
+    }
 }
 
 pub fn apply_friction(
     mut query: Query<(&mut Velocity, &mut Transform)>,
     time: Res<Time>,
-    guide: Option<Res<mapping::GuidanceField>>,
+    guide: Option<Res<guidance::GuidanceField>>,
 ) {
     let delta = time.delta_seconds();
     for (mut v, t) in query.iter_mut() {
@@ -56,6 +138,32 @@ pub fn apply_friction(
     }
 }
 
+pub fn simulate_engine(mut query: Query<(&mut Engine, &Velocity, &Angle)>, time: Res<Time>) {
+    let delta = time.delta_seconds();
+    if delta <= 0.0 {
+        return;
+    }
+
+    for (mut engine, v, a) in query.iter_mut() {
+        let speed = v.0.dot(Vec2::from_angle(a.0)).max(0.0);
+
+        // The load is the thrust needed to explain the change of speed (after
+        // allowing for the basic friction) as a fraction of full throttle.
+        let thrust = (speed - engine.forward_speed) / delta + 1.2 * speed;
+        let load = (thrust / 560.0).clamp(0.0, 1.0);
+        engine.load += (load - engine.load) * (delta * 8.0).min(1.0);
+        engine.forward_speed = speed;
+
+        let rpm = |gear: usize| IDLE_RPM + speed * GEAR_RATIOS[gear];
+        if rpm(engine.gear) > SHIFT_UP_RPM && engine.gear + 1 < GEAR_RATIOS.len() {
+            engine.gear += 1;
+        } else if rpm(engine.gear) < SHIFT_DOWN_RPM && engine.gear > 0 {
+            engine.gear -= 1;
+        }
+        engine.rpm = rpm(engine.gear);
+    }
+}
+
 pub fn apply_velocity(mut query: Query<(&Velocity, &mut Transform)>, time: Res<Time>) {
     let delta = time.delta_seconds();
     for (v, mut t) in query.iter_mut() {
@@ -65,15 +173,29 @@ pub fn apply_velocity(mut query: Query<(&Velocity, &mut Transform)>, time: Res<T
 }
 
 pub fn collision_detection(
-    mut query: Query<(&CollisionBox, &mut Transform, &mut Velocity)>,
+    mut query: Query<
+        (
+            Entity,
+            &CollisionBox,
+            &mut Transform,
+            &mut Velocity,
+            Option<&Mass>,
+            Has<Racer>,
+        ),
+        Without<Ghost>,
+    >,
     prefs: Res<Preferences>,
     mut gizmos: Gizmos,
+    mut events: EventWriter<CarCarCollision>,
 ) {
     let mut colliders = query.iter_mut().collect::<Vec<_>>();
     let mut pairs = colliders.pairs_mut();
     // pairs_mut() does not return an iterator (due to borrowing rules) but we
     // create a similar loop using while-let
-    while let Some(((CollisionBox(apoly), atf, av), (CollisionBox(bpoly), btf, bv))) = pairs.next()
+    while let Some((
+        (a, CollisionBox(apoly), atf, av, amass, a_is_car),
+        (b, CollisionBox(bpoly), btf, bv, bmass, b_is_car),
+    )) = pairs.next()
     {
         let mut abox = apoly.transform(&atf);
         let mut bbox = bpoly.transform(&btf);
@@ -83,14 +205,44 @@ pub fn collision_detection(
         }
 
         if abox.is_touching(&bbox) {
-            std::mem::swap(&mut av.0, &mut bv.0);
-
             let a2 = vec2(atf.translation.x, atf.translation.y);
             let b2 = vec2(btf.translation.x, btf.translation.y);
-            let nudge = Vec3::from(((b2 - a2).normalize() * 0.5, 0.0));
+            let point = abox
+                .iter()
+                .find(|&&pt| bbox.contains_point(pt))
+                .or_else(|| bbox.iter().find(|&&pt| abox.contains_point(pt)))
+                .copied()
+                .unwrap_or((a2 + b2) / 2.0);
+
+            // Elastic collision. For two cars (which have the same mass) this
+            // simply swaps the velocities but light objects get knocked
+            // flying by heavy ones.
+            let ma = amass.map_or(1.0, |m| m.0);
+            let mb = bmass.map_or(1.0, |m| m.0);
+            let total = ma + mb;
+            let (old_a, old_b) = (av.0, bv.0);
+            (av.0, bv.0) = (
+                ((ma - mb) * av.0 + 2.0 * mb * bv.0) / total,
+                ((mb - ma) * bv.0 + 2.0 * ma * av.0) / total,
+            );
+            if *a_is_car || *b_is_car {
+                events.send(CarCarCollision {
+                    a: *a,
+                    b: *b,
+                    point,
+                    normal: (b2 - a2).normalize_or_zero(),
+                    impulse_a: (av.0 - old_a).length(),
+                    impulse_b: (bv.0 - old_b).length(),
+                });
+            }
+
+            // The lighter object does most of the moving to separate them
+            let dir = Vec3::from(((b2 - a2).normalize(), 0.0));
+            let anudge = dir * (mb / total);
+            let bnudge = dir * (ma / total);
             while abox.is_touching(&bbox) {
-                atf.translation -= nudge;
-                btf.translation += nudge;
+                atf.translation -= anudge;
+                btf.translation += bnudge;
 
                 abox = apoly.transform(&atf);
                 bbox = bpoly.transform(&btf);
@@ -100,39 +252,69 @@ pub fn collision_detection(
 }
 
 pub fn fixed_collision_detection(
-    mut cars: Query<(&CollisionBox, &mut Transform, &mut Velocity)>,
-    scenery: Query<(&CollisionBox, &mut Transform, Without<Velocity>)>,
+    mut cars: Query<
+        (
+            Entity,
+            &CollisionBox,
+            &mut Transform,
+            &mut Velocity,
+            Has<Racer>,
+        ),
+        Without<Ghost>,
+    >,
+    scenery: Query<(Entity, &CollisionBox, &mut Transform, Without<Velocity>)>,
     prefs: Res<Preferences>,
     mut gizmos: Gizmos,
+    mut events: EventWriter<CarSceneryCollision>,
 ) {
     if prefs.debug_low() {
-        for (CollisionBox(obj_poly), obj_tf, _) in scenery.iter() {
+        for (_, CollisionBox(obj_poly), obj_tf, _) in scenery.iter() {
             let obj_box = obj_poly.transform(&obj_tf);
             obj_box.draw(&mut gizmos);
         }
     }
 
-    for (CollisionBox(car_poly), mut car_tf, mut car_vel) in cars.iter_mut() {
+    for (car, CollisionBox(car_poly), mut car_tf, mut car_vel, is_car) in cars.iter_mut() {
         let mut car_box = car_poly.transform(&car_tf);
 
-        for (CollisionBox(obj_poly), obj_tf, _) in scenery.iter() {
+        for (obj, CollisionBox(obj_poly), obj_tf, _) in scenery.iter() {
             let obj_box = obj_poly.transform(&obj_tf);
 
             if car_box.is_touching(&obj_box) {
+                let old_vel = car_vel.0;
                 let car_pt = car_box.iter().find(|&&pt| obj_box.contains_point(pt));
-                if let Some(&pt) = car_pt {
+                let point = if let Some(&pt) = car_pt {
                     car_vel.0 = reflect_against_line(car_vel.0, obj_box.closest_edge_to_point(pt));
+                    pt
                 } else if let Some((&prev, &pt, &next)) = obj_box
                     .iter_segments()
                     .find(|(_, &pt, _)| car_box.contains_point(pt))
                 {
                     car_vel.0 = reflect_against_segment(car_vel.0, (prev, pt, next));
+                    pt
                 } else {
                     unreachable!();
-                }
+                };
 
                 let ct = vec2(car_tf.translation.x, car_tf.translation.y);
                 let ot = vec2(obj_tf.translation.x, obj_tf.translation.y);
+
+                // Reflection only changes the velocity along the normal so we can
+                // recover the normal from the change in velocity (unless the car
+                // was only grazing the scenery).
+                let change = car_vel.0 - old_vel;
+                if is_car {
+                    events.send(CarSceneryCollision {
+                        car,
+                        scenery: obj,
+                        point,
+                        normal: change
+                            .try_normalize()
+                            .unwrap_or((ct - ot).normalize_or_zero()),
+                        impulse: change.length(),
+                    });
+                }
+
                 let nudge = Vec3::from(((ct - ot).normalize(), 0.0));
                 while car_box.is_touching(&obj_box) {
                     car_tf.translation += nudge;
@@ -142,3 +324,34 @@ pub fn fixed_collision_detection(
         }
     }
 }
+
+/// Report collisions and (with debug visualizations enabled) show the
+/// contact point and normal.
+pub fn debug_collisions(
+    mut car_car: EventReader<CarCarCollision>,
+    mut car_scenery: EventReader<CarSceneryCollision>,
+    prefs: Res<Preferences>,
+    mut gizmos: Gizmos,
+) {
+    let mut draw = |point: Vec2, normal: Vec2, impulse: f32| {
+        if prefs.debug_low() {
+            gizmos.circle_2d(point, 4.0, Color::RED);
+            gizmos.line_2d(point, point + normal * impulse.max(20.0), Color::RED);
+        }
+    };
+
+    for e in car_car.read() {
+        log::debug!(
+            "{:?} hit {:?} (impulse {}/{})",
+            e.a,
+            e.b,
+            e.impulse_a,
+            e.impulse_b
+        );
+        draw(e.point, e.normal, e.impulse_a.max(e.impulse_b));
+    }
+    for e in car_scenery.read() {
+        log::debug!("{:?} hit {:?} (impulse {})", e.car, e.scenery, e.impulse);
+        draw(e.point, e.normal, e.impulse);
+    }
+}
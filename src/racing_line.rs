@@ -0,0 +1,416 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+//! Whole-track racing line for the AI drivers.
+//!
+//! When a level is loaded we trace the middle of the track by following the
+//! ridge of the [GuidanceField] (i.e. staying as far from the edges as we
+//! can) all the way round from the starting grid.
+//! That centreline is then pulled tight (within the limits of the track) to
+//! cut the corners, and a target speed is calculated for every point from the
+//! curvature of the line. Finally the speeds are propagated backwards so
+//! that the AI lifts off in time to make the next corner.
+//!
+//! Level designers can also draw the line themselves (see
+//! [RacingLine::from_path]), in which case the automatic line is not
+//! generated.
+
+use bevy::{log, prelude::*};
+use std::f32::consts::PI;
+
+use crate::{guidance::GuidanceField, physics, GameState, Racer};
+
+/// Distance between points when tracing the centreline.
+const STEP: f32 = 64.0;
+
+/// Number of steps taken to settle into the middle of the track before we
+/// start recording the line.
+const SETTLE_STEPS: usize = 12;
+
+/// Give up if the trace doesn't get back to the start after this many steps.
+const MAX_STEPS: usize = 4000;
+
+/// How far the racing line is allowed to wander from the centre of the
+/// track (the guidance field is 255 in the middle of a wide track).
+const MIN_GUIDANCE: i32 = 140;
+
+/// Fastest the AI will go, which is a little below the top speed of the car.
+const MAX_SPEED: f32 = 470.0;
+
+/// Sideways acceleration the cars can manage in a corner.
+const CORNERING_ACCEL: f32 = 350.0;
+
+/// Maximum rate at which the cars can turn (radians per second).
+const TURN_RATE: f32 = 3.0;
+
+/// Deceleration used to place the braking points. There are no brakes so
+/// this is just lifting off and letting friction slow the car.
+const LIFT_OFF_DECEL: f32 = 350.0;
+
+#[derive(Default)]
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnEnter(GameState::NextLevel), remove_racing_line)
+            .add_systems(Update, generate_racing_line);
+    }
+}
+
+/// How an individual driver follows the racing line.
+#[derive(Clone, Copy, Debug)]
+pub struct DrivingStyle {
+    /// Distance to the left (or, if negative, the right) of the line.
+    pub offset: f32,
+    /// Scale factor for the cornering speeds.
+    pub cornering: f32,
+    /// Scale factor for the speed on the straights.
+    pub top_speed: f32,
+}
+
+impl Default for DrivingStyle {
+    fn default() -> Self {
+        Self {
+            offset: 0.0,
+            cornering: 1.0,
+            top_speed: 1.0,
+        }
+    }
+}
+
+/// A closed loop around the track with a target speed at every point.
+#[derive(Resource, Debug)]
+pub struct RacingLine {
+    points: Vec<Vec2>,
+    speeds: Vec<f32>,
+    designed: bool,
+}
+
+impl RacingLine {
+    /// Trace the racing line starting from `start` and heading in direction
+    /// `heading` (in radians).
+    pub fn from_guidance(guide: &GuidanceField, start: Vec2, heading: f32) -> Option<Self> {
+        let centreline = trace_centreline(guide, start, heading)?;
+        let points = tighten(guide, centreline);
+        let speeds = speed_profile(&points, &vec![None; points.len()]);
+        Some(Self {
+            points,
+            speeds,
+            designed: false,
+        })
+    }
+
+    /// Build the racing line from a path drawn by the level designer.
+    ///
+    /// The path is always treated as a loop. Each vertex can optionally
+    /// have a target speed which caps the speed of the AI at that point.
+    pub fn from_path(path: &[Vec2], vertex_speeds: &[Option<f32>]) -> Option<Self> {
+        if path.len() < 3 {
+            return None;
+        }
+
+        // Resample the path so the points are evenly spaced
+        let mut points = Vec::new();
+        let mut limits = Vec::new();
+        for (i, &from) in path.iter().enumerate() {
+            let to = path[(i + 1) % path.len()];
+            let steps = (from.distance(to) / STEP).ceil().max(1.0) as usize;
+            for n in 0..steps {
+                points.push(from.lerp(to, n as f32 / steps as f32));
+                limits.push(if n == 0 {
+                    vertex_speeds.get(i).copied().flatten()
+                } else {
+                    None
+                });
+            }
+        }
+
+        // Designers draw corners as sharp angles; round them off a little
+        let n = points.len();
+        for _ in 0..4 {
+            for i in 0..n {
+                let prev = points[(i + n - 1) % n];
+                let next = points[(i + 1) % n];
+                points[i] = points[i].lerp((prev + next) / 2.0, 0.5);
+            }
+        }
+
+        let speeds = speed_profile(&points, &limits);
+        Some(Self {
+            points,
+            speeds,
+            designed: true,
+        })
+    }
+
+    /// Whether the line was drawn by the level designer.
+    pub fn is_designed(&self) -> bool {
+        self.designed
+    }
+
+    fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn point(&self, index: usize) -> Vec2 {
+        self.points[index % self.len()]
+    }
+
+    pub fn speed(&self, index: usize) -> f32 {
+        self.speeds[index % self.len()]
+    }
+
+    /// Direction of travel along the line at `index`.
+    pub fn direction(&self, index: usize) -> Vec2 {
+        (self.point(index + 1) - self.point(index)).normalize_or_zero()
+    }
+
+    /// Find the point closest to `pos`.
+    ///
+    /// `hint` is the result from the previous frame. Cars don't move very far
+    /// each frame so we only look a little way forward from the hint unless
+    /// the car has wandered far from the line.
+    pub fn nearest(&self, pos: Vec2, hint: usize) -> usize {
+        let distance = |i: &usize| self.point(*i).distance_squared(pos);
+        let local = (hint..hint + 16)
+            .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+            .unwrap_or(hint);
+
+        if distance(&local) < (5.0 * STEP).powi(2) {
+            local % self.len()
+        } else {
+            (0..self.len())
+                .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+                .unwrap_or(0)
+        }
+    }
+
+    /// Index of the point roughly `distance` further along the line.
+    pub fn ahead(&self, index: usize, distance: f32) -> usize {
+        let mut travelled = 0.0;
+        let mut i = index;
+        while travelled < distance && i < index + self.len() {
+            travelled += self.point(i).distance(self.point(i + 1));
+            i += 1;
+        }
+        i % self.len()
+    }
+
+    /// Distance along the line from `b` to `a`.
+    ///
+    /// The result is positive if `a` is ahead of `b`. Since the line is a
+    /// loop the gap is never more than half a lap.
+    pub fn gap(&self, a: usize, b: usize) -> f32 {
+        let len = self.len() as isize;
+        let mut diff = (a as isize - b as isize).rem_euclid(len);
+        if diff > len / 2 {
+            diff -= len;
+        }
+        diff as f32 * self.spacing()
+    }
+
+    /// Average distance between the points.
+    fn spacing(&self) -> f32 {
+        let total: f32 = (0..self.len())
+            .map(|i| self.point(i).distance(self.point(i + 1)))
+            .sum();
+        total / self.len() as f32
+    }
+
+    /// Pure pursuit of the racing line.
+    ///
+    /// Returns the steering (-1.0 for full right lock to 1.0 for full left)
+    /// and throttle (0.0 to 1.0) needed to follow the line. `hint` is where
+    /// the car was last seen on the line.
+    pub fn drive(
+        &self,
+        hint: usize,
+        pos: Vec2,
+        angle: f32,
+        v: Vec2,
+        style: &DrivingStyle,
+    ) -> (f32, f32) {
+        let index = self.nearest(pos, hint);
+
+        let speed = v.length();
+        let ahead = self.ahead(index, 120.0 + 0.5 * speed);
+        let direction = (self.point(ahead + 1) - self.point(ahead)).normalize_or_zero();
+        let target = self.point(ahead) + style.offset * direction.perp();
+        let to_target = target - pos;
+        let error = normalize_angle(to_target.y.atan2(to_target.x) - angle);
+        let steer = (4.0 * error).clamp(-1.0, 1.0);
+
+        // Look slightly ahead for the target speed to allow for the time it
+        // takes to react
+        let target_speed = (style.cornering * self.speed(self.ahead(index, 0.25 * speed)))
+            .min(style.top_speed * MAX_SPEED);
+        let throttle = ((target_speed - speed) / 40.0).clamp(0.0, 1.0);
+
+        (steer, throttle)
+    }
+
+    pub fn draw(&self, gizmos: &mut Gizmos) {
+        for i in 0..self.len() {
+            let colour = Color::rgb(
+                1.0 - self.speed(i) / MAX_SPEED,
+                self.speed(i) / MAX_SPEED,
+                0.0,
+            );
+            gizmos.line_2d(self.point(i), self.point(i + 1), colour);
+        }
+    }
+}
+
+fn normalize_angle(mut a: f32) -> f32 {
+    while a > PI {
+        a -= 2.0 * PI;
+    }
+    while a < -PI {
+        a += 2.0 * PI;
+    }
+    a
+}
+
+/// Follow the ridge of the guidance field until we get back to where we
+/// started.
+fn trace_centreline(guide: &GuidanceField, start: Vec2, heading: f32) -> Option<Vec<Vec2>> {
+    let mut pos = start;
+    let mut heading = heading;
+    let mut start_heading = heading;
+    let mut points = Vec::new();
+
+    for step in 0..MAX_STEPS {
+        // Look ahead across a spread of directions and pick the one that
+        // stays furthest from the edges (both for the next step and a little
+        // further on, so we see corners coming), with a small preference for
+        // going straight on.
+        let distance = |pt: Vec2| guide.distance(&pt).unwrap_or(f32::MIN);
+        let (best, _) = (-8_i32..=8)
+            .map(|n| {
+                let turn = n as f32 * PI / 48.0;
+                let dir = Vec2::from_angle(heading + turn);
+                let score = distance(pos + STEP * dir) + distance(pos + 3.0 * STEP * dir)
+                    - 0.005 * n.abs() as f32;
+                (turn, score)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+
+        heading = normalize_angle(heading + best);
+        pos += STEP * Vec2::from_angle(heading);
+        if guide.get(&pos) == 0 {
+            log::warn!("Racing line left the track at {pos}");
+            return None;
+        }
+
+        if step == SETTLE_STEPS {
+            points.clear();
+            start_heading = heading;
+        }
+        points.push(pos);
+
+        // We are back at the start when we are close to it *and* heading the
+        // same way (otherwise we might just be crossing over it)
+        let same_way = Vec2::from_angle(heading).dot(Vec2::from_angle(start_heading)) > 0.5;
+        if step > SETTLE_STEPS + 8 && same_way && pos.distance(points[0]) < 2.0 * STEP {
+            return Some(points);
+        }
+    }
+
+    log::warn!("Racing line didn't get back to the start");
+    None
+}
+
+/// Pull the line tight, staying on the track, so that it cuts the corners.
+fn tighten(guide: &GuidanceField, mut points: Vec<Vec2>) -> Vec<Vec2> {
+    let n = points.len();
+    for _ in 0..100 {
+        for i in 0..n {
+            let prev = points[(i + n - 1) % n];
+            let next = points[(i + 1) % n];
+            let moved = points[i].lerp((prev + next) / 2.0, 0.3);
+            if guide.get(&moved) >= MIN_GUIDANCE && guide.is_on_track(&moved) {
+                points[i] = moved;
+            }
+        }
+    }
+    points
+}
+
+/// Calculate the target speed at each point on the line, given an optional
+/// speed limit at each point.
+fn speed_profile(points: &[Vec2], limits: &[Option<f32>]) -> Vec<f32> {
+    let n = points.len();
+    let mut speeds = (0..n)
+        .map(|i| {
+            let a = points[(i + n - 1) % n];
+            let b = points[i];
+            let c = points[(i + 1) % n];
+
+            // Menger curvature (the inverse of the radius of the circle that
+            // passes through all three points)
+            let area2 = (b - a).perp_dot(c - a).abs();
+            let curvature = 2.0 * area2 / (a.distance(b) * b.distance(c) * a.distance(c)).max(1.0);
+
+            MAX_SPEED
+                .min((CORNERING_ACCEL / curvature).sqrt())
+                .min(TURN_RATE / curvature)
+                .min(limits[i].unwrap_or(MAX_SPEED))
+        })
+        .collect::<Vec<_>>();
+
+    // Work backwards (twice, because it is a loop) to find where we need to
+    // lift off for each corner.
+    for j in (0..2 * n).rev() {
+        let i = j % n;
+        let next = (i + 1) % n;
+        let distance = points[i].distance(points[next]);
+        speeds[i] = speeds[i].min((speeds[next].powi(2) + 2.0 * LIFT_OFF_DECEL * distance).sqrt());
+    }
+
+    speeds
+}
+
+fn remove_racing_line(mut commands: Commands) {
+    commands.remove_resource::<RacingLine>();
+}
+
+/// Build the racing line once both the guidance field and the cars for a new
+/// level are available.
+///
+/// The cars are always placed on the grid facing the right way so we start
+/// the trace from the first of them. Nothing is generated if the level came
+/// with its own racing line.
+fn generate_racing_line(
+    mut commands: Commands,
+    guide: Option<Res<GuidanceField>>,
+    line: Option<Res<RacingLine>>,
+    cars: Query<(Entity, &Transform, &physics::Angle), With<Racer>>,
+    mut pending: Local<bool>,
+) {
+    let Some(guide) = guide else {
+        return;
+    };
+    if guide.is_changed() {
+        *pending = true;
+    }
+    if !*pending {
+        return;
+    }
+    if line.is_some_and(|line| line.is_designed()) {
+        *pending = false;
+        return;
+    }
+
+    let Some((_, xform, angle)) = cars.iter().min_by_key(|(entity, _, _)| *entity) else {
+        return;
+    };
+    *pending = false;
+
+    match RacingLine::from_guidance(&guide, xform.translation.truncate(), angle.0) {
+        Some(line) => {
+            log::info!("Generated racing line with {} points", line.len());
+            commands.insert_resource(line);
+        }
+        None => log::warn!("Cannot generate racing line, falling back to whiskers"),
+    }
+}
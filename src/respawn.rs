@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+//! Put cars that have got lost back on the track.
+//!
+//! Cars go back to the centre of the last checkpoint they passed, pointing
+//! along the track, and are ghosts for a little while afterwards so they
+//! can't land on top of (or be hit by) another car.
+
+#![allow(clippy::type_complexity)]
+
+use bevy::{log, prelude::*};
+
+use crate::{controls::Controls, guidance::GuidanceField, physics, GameState, Racer};
+
+/// How long, in seconds, a car is a ghost after it is put back on the track.
+const GRACE_TIME: f32 = 2.0;
+
+/// A car more than `LOST_DISTANCE` tiles from the edge of the track for
+/// `LOST_TIME` seconds is lost.
+const LOST_DISTANCE: f32 = 3.0;
+const LOST_TIME: f32 = 1.5;
+
+/// A human driver that has their foot down but has moved less than
+/// `STUCK_DISTANCE` in `STUCK_TIME` seconds, and never gone faster than
+/// `STUCK_SPEED`, is stuck (the AI has its own, more patient, way of dealing
+/// with this).
+const STUCK_SPEED: f32 = 40.0;
+const STUCK_TIME: f32 = 4.0;
+const STUCK_DISTANCE: f32 = 60.0;
+
+#[derive(Default)]
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<Respawn>().add_systems(
+            Update,
+            (
+                find_lost_cars.run_if(in_state(GameState::Game)),
+                respawn_cars.after(find_lost_cars),
+                fade_ghosts.after(respawn_cars),
+            ),
+        );
+    }
+}
+
+/// Send a car back to its last checkpoint.
+#[derive(Event, Clone, Debug)]
+pub struct Respawn {
+    pub car: Entity,
+}
+
+/// Keep track of how long a car has been in trouble.
+#[derive(Component, Debug, Default)]
+pub struct Recovery {
+    lost_time: f32,
+    stuck_at: Vec2,
+    stuck_time: f32,
+}
+
+/// Respawn cars whose driver has asked to reset and cars that are a long way
+/// from the track or stuck.
+fn find_lost_cars(
+    mut cars: Query<(
+        Entity,
+        &Transform,
+        &physics::Velocity,
+        &mut Recovery,
+        Option<&Controls>,
+        Option<&physics::Ghost>,
+    )>,
+    guide: Option<Res<GuidanceField>>,
+    time: Res<Time>,
+    mut respawns: EventWriter<Respawn>,
+) {
+    let delta = time.delta_seconds();
+
+    for (car, xform, v, mut recovery, controls, ghost) in cars.iter_mut() {
+        if ghost.is_some() {
+            continue;
+        }
+        let pos = xform.translation.truncate();
+
+        let lost = guide
+            .as_ref()
+            .is_some_and(|guide| guide.distance(&pos).is_none_or(|d| d < -LOST_DISTANCE));
+        recovery.lost_time = if lost {
+            recovery.lost_time + delta
+        } else {
+            0.0
+        };
+
+        let stuck = controls.is_some_and(|c| c.throttle > 0.5) && v.0.length() < STUCK_SPEED;
+        if !stuck {
+            recovery.stuck_time = 0.0;
+        } else if recovery.stuck_time == 0.0 {
+            recovery.stuck_at = pos;
+            recovery.stuck_time = delta;
+        } else if pos.distance(recovery.stuck_at) < STUCK_DISTANCE {
+            recovery.stuck_time += delta;
+        } else {
+            recovery.stuck_time = 0.0;
+        }
+
+        if controls.is_some_and(|c| c.reset)
+            || recovery.lost_time > LOST_TIME
+            || recovery.stuck_time > STUCK_TIME
+        {
+            log::debug!("{car:?} is lost at {pos}, respawning");
+            respawns.send(Respawn { car });
+        }
+    }
+}
+
+fn respawn_cars(
+    mut commands: Commands,
+    mut events: EventReader<Respawn>,
+    mut cars: Query<(
+        &mut Transform,
+        &mut physics::Angle,
+        &mut physics::Velocity,
+        &mut Racer,
+        Option<&mut Recovery>,
+    )>,
+) {
+    for Respawn { car } in events.read() {
+        let Ok((mut xform, mut a, mut v, mut r, recovery)) = cars.get_mut(*car) else {
+            continue;
+        };
+        let Some(checkpoint) = r.last_checkpoint else {
+            continue;
+        };
+
+        xform.translation = checkpoint
+            .translation
+            .truncate()
+            .extend(xform.translation.z);
+        xform.rotation = checkpoint.rotation;
+        *a = physics::Angle::from_quat(checkpoint.rotation);
+        v.0 = Vec2::ZERO;
+
+        // Don't treat the jump as a tile skip (or as going the wrong way)
+        r.last_tile = None;
+        r.wrong_way = 0.0;
+        if let Some(mut recovery) = recovery {
+            *recovery = Recovery::default();
+        }
+
+        commands.entity(*car).insert(physics::Ghost(GRACE_TIME));
+    }
+}
+
+/// Ghosts are drawn see-through until they become solid again.
+fn fade_ghosts(
+    mut commands: Commands,
+    mut ghosts: Query<(Entity, &mut physics::Ghost, Option<&mut TextureAtlasSprite>)>,
+    time: Res<Time>,
+) {
+    for (car, mut ghost, sprite) in ghosts.iter_mut() {
+        ghost.0 -= time.delta_seconds();
+        let solid = ghost.0 <= 0.0;
+        if let Some(mut sprite) = sprite {
+            sprite.color.set_a(if solid { 1.0 } else { 0.5 });
+        }
+        if solid {
+            commands.entity(car).remove::<physics::Ghost>();
+        }
+    }
+}
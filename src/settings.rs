@@ -0,0 +1,291 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+use bevy::{ecs::system::SystemParam, log, prelude::*};
+use clap::{parser::ValueSource, ArgMatches};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use thiserror::Error;
+
+use crate::{
+    controls::Bindings,
+    menu::{
+        Difficulty, DigitalSpeedo, DisplayMode, MinimapCorner, MinimapOpacity, Resolution,
+        RubberBanding, ViewWidth, Volume, Vsync,
+    },
+    Preferences,
+};
+
+#[derive(Default)]
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PostUpdate, save_settings);
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SettingsError {
+    #[error("No config directory for this platform")]
+    NoConfigDir,
+    #[error("Could not access settings file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Could not parse settings file: {0}")]
+    Parse(#[from] ron::error::SpannedError),
+    #[error("Could not serialize settings: {0}")]
+    Serialize(#[from] ron::Error),
+}
+
+/// Everything that is remembered between runs.
+///
+/// Missing fields take their default value so that settings files written by
+/// older versions can still be loaded.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub volume: u32,
+    pub window: bool,
+    pub resolution: Resolution,
+    pub vsync: Vsync,
+    pub view_width: ViewWidth,
+    pub minimap_corner: MinimapCorner,
+    pub minimap_opacity: MinimapOpacity,
+    pub digital_speedo: DigitalSpeedo,
+    pub debug: u8,
+    pub jenny_mode: bool,
+    pub dead_zone: f32,
+    pub bindings: Bindings,
+    pub difficulty: Difficulty,
+    pub rubber_banding: RubberBanding,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            volume: 7,
+            window: false,
+            resolution: Resolution(1280, 720),
+            vsync: Vsync(true),
+            view_width: ViewWidth(1920),
+            minimap_corner: MinimapCorner::TopRight,
+            minimap_opacity: MinimapOpacity(75),
+            digital_speedo: DigitalSpeedo(false),
+            debug: 0,
+            jenny_mode: false,
+            dead_zone: 0.1,
+            bindings: Bindings::default(),
+            difficulty: Difficulty::Normal,
+            rubber_banding: RubberBanding(true),
+        }
+    }
+}
+
+impl Settings {
+    pub fn path() -> Result<PathBuf, SettingsError> {
+        let mut path = dirs::config_dir().ok_or(SettingsError::NoConfigDir)?;
+        path.push("tdr2024");
+        path.push("settings.ron");
+        Ok(path)
+    }
+
+    /// Read the settings file, falling back to the defaults if it is missing
+    /// or cannot be parsed.
+    pub fn load() -> Self {
+        let settings = Self::path().and_then(|path| {
+            let text = std::fs::read_to_string(&path)?;
+            log::info!("Loading settings from {}", path.display());
+            Ok(ron::from_str::<Settings>(&text)?)
+        });
+
+        match settings {
+            Ok(mut settings) => {
+                settings.bindings.add_missing();
+                settings
+            }
+            Err(SettingsError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                Self::default()
+            }
+            Err(e) => {
+                log::warn!("{e}, using default settings");
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<(), SettingsError> {
+        let path = Self::path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        std::fs::write(&path, text)?;
+        log::debug!("Saved settings to {}", path.display());
+        Ok(())
+    }
+
+    /// Copy the settings into the preferences, unless the preference was
+    /// given on the command line (which always wins).
+    ///
+    /// Returns the values, from the file, of the preferences that were
+    /// overridden so that they can be saved in place of the command-line
+    /// values.
+    pub fn apply(&self, prefs: &mut Preferences, matches: &ArgMatches) -> CommandLine {
+        let from_file = |id| matches.value_source(id) != Some(ValueSource::CommandLine);
+        let mut command_line = CommandLine::default();
+
+        if from_file("window") {
+            prefs.window = self.window;
+        } else {
+            command_line.window = Some(Override::new(self.window, prefs.window));
+        }
+        if from_file("debug") {
+            prefs.debug = self.debug;
+        } else {
+            command_line.debug = Some(Override::new(self.debug, prefs.debug));
+        }
+        if from_file("jenny_mode") {
+            prefs.jenny_mode = self.jenny_mode;
+        } else {
+            command_line.jenny_mode = Some(Override::new(self.jenny_mode, prefs.jenny_mode));
+        }
+        if from_file("dead_zone") {
+            prefs.dead_zone = self.dead_zone;
+        } else {
+            command_line.dead_zone = Some(Override::new(self.dead_zone, prefs.dead_zone));
+        }
+
+        command_line
+    }
+
+    fn capture(
+        prefs: &Preferences,
+        volume: &Volume,
+        bindings: &Bindings,
+        display: &Display,
+        game: &Game,
+        command_line: &CommandLine,
+    ) -> Self {
+        Self {
+            volume: volume.0,
+            window: Override::keep(command_line.window, *display.mode == DisplayMode::Windowed),
+            resolution: *display.resolution,
+            vsync: *display.vsync,
+            view_width: *display.view_width,
+            minimap_corner: *display.minimap_corner,
+            minimap_opacity: *display.minimap_opacity,
+            digital_speedo: *display.digital_speedo,
+            debug: Override::keep(command_line.debug, prefs.debug),
+            jenny_mode: Override::keep(command_line.jenny_mode, prefs.jenny_mode),
+            dead_zone: Override::keep(command_line.dead_zone, prefs.dead_zone),
+            bindings: bindings.clone(),
+            difficulty: *game.difficulty,
+            rubber_banding: *game.rubber_banding,
+        }
+    }
+}
+
+/// The settings that were given on the command line.
+///
+/// Command-line flags only last for one run so, when the settings are saved,
+/// the values from the file are written back instead (unless the setting has
+/// since been changed in the menu).
+#[derive(Resource, Debug, Default)]
+pub struct CommandLine {
+    window: Option<Override<bool>>,
+    debug: Option<Override<u8>>,
+    jenny_mode: Option<Override<bool>>,
+    dead_zone: Option<Override<f32>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Override<T> {
+    file: T,
+    command_line: T,
+}
+
+impl<T: Copy + PartialEq> Override<T> {
+    fn new(file: T, command_line: T) -> Self {
+        Self { file, command_line }
+    }
+
+    /// Pick the value to save for a setting that is currently `live`.
+    fn keep(this: Option<Self>, live: T) -> T {
+        match this {
+            Some(o) if live == o.command_line => o.file,
+            _ => live,
+        }
+    }
+}
+
+#[derive(SystemParam)]
+struct Display<'w> {
+    mode: Res<'w, DisplayMode>,
+    resolution: Res<'w, Resolution>,
+    vsync: Res<'w, Vsync>,
+    view_width: Res<'w, ViewWidth>,
+    minimap_corner: Res<'w, MinimapCorner>,
+    minimap_opacity: Res<'w, MinimapOpacity>,
+    digital_speedo: Res<'w, DigitalSpeedo>,
+}
+
+impl Display<'_> {
+    fn is_changed(&self) -> bool {
+        self.mode.is_changed()
+            || self.resolution.is_changed()
+            || self.vsync.is_changed()
+            || self.view_width.is_changed()
+            || self.minimap_corner.is_changed()
+            || self.minimap_opacity.is_changed()
+            || self.digital_speedo.is_changed()
+    }
+}
+
+#[derive(SystemParam)]
+struct Game<'w> {
+    difficulty: Res<'w, Difficulty>,
+    rubber_banding: Res<'w, RubberBanding>,
+}
+
+impl Game<'_> {
+    fn is_changed(&self) -> bool {
+        self.difficulty.is_changed() || self.rubber_banding.is_changed()
+    }
+}
+
+/// Write the settings back to disc whenever they are changed.
+///
+/// The resources that hold the settings can also change for other reasons
+/// (`Preferences` is updated each time a level is loaded) so we keep a copy
+/// of the last settings seen and only write the file when they really differ.
+fn save_settings(
+    prefs: Res<Preferences>,
+    volume: Res<Volume>,
+    bindings: Res<Bindings>,
+    display: Display,
+    game: Game,
+    command_line: Res<CommandLine>,
+    mut last: Local<Option<Settings>>,
+) {
+    if !prefs.is_changed()
+        && !volume.is_changed()
+        && !bindings.is_changed()
+        && !display.is_changed()
+        && !game.is_changed()
+    {
+        return;
+    }
+
+    let settings = Settings::capture(&prefs, &volume, &bindings, &display, &game, &command_line);
+    match last.as_ref() {
+        // The first time through is just the resources being added
+        None => *last = Some(settings),
+        Some(prev) if *prev != settings => {
+            if let Err(e) = settings.save() {
+                log::error!("{e}");
+            }
+            *last = Some(settings);
+        }
+        _ => (),
+    }
+}
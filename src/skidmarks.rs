@@ -0,0 +1,203 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+#![allow(clippy::type_complexity)]
+
+use bevy::{math::vec2, prelude::*};
+use std::collections::VecDeque;
+
+use crate::{physics, GameState, LevelComponent, Racer};
+
+/// Most skid marks that can be on the track at once. Once the pool is full
+/// the oldest marks are recycled.
+const MAX_SKIDMARKS: usize = 512;
+
+/// How long, in seconds, a skid mark takes to fade away.
+const FADE_TIME: f32 = 12.0;
+
+/// Sideways speed (world units per second) at which the tyres start to mark
+/// the track.
+const SLIP_THRESHOLD: f32 = 200.0;
+
+/// Loss of forward speed (world units per second per second) at which the
+/// tyres start to mark the track. Lifting off at top speed slows the car
+/// by roughly 600.
+const BRAKING_THRESHOLD: f32 = 900.0;
+
+/// Length of the individual marks that make up a skid.
+const SEGMENT_LENGTH: f32 = 16.0;
+
+/// Position of the rear wheels relative to the centre of the car (x is
+/// forwards).
+const REAR_WHEELS: [Vec2; 2] = [vec2(-40.0, 24.0), vec2(-40.0, -24.0)];
+
+const SKIDMARK_IMAGE: &str =
+    "embedded://tdr2024/assets/kenney_racing-pack/PNG/Objects/skidmark_long_1.png";
+
+/// Just above the track but below hazards and cars.
+const SKIDMARK_Z: f32 = 1.2;
+
+#[derive(Default)]
+pub struct Plugin;
+
+impl bevy::app::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SkidMarkPool>()
+            .add_systems(OnEnter(GameState::NextLevel), clear_pool)
+            .add_systems(
+                Update,
+                (
+                    add_skid_trails,
+                    lay_skid_marks
+                        .after(physics::apply_friction)
+                        .run_if(in_state(GameState::Game)),
+                    fade_skid_marks,
+                ),
+            );
+    }
+}
+
+/// Every skid mark, oldest first.
+#[derive(Resource, Debug, Default)]
+struct SkidMarkPool(VecDeque<Entity>);
+
+#[derive(Component, Debug)]
+struct SkidMark {
+    age: f32,
+}
+
+/// Where each rear wheel was when it last left a mark.
+#[derive(Component, Debug, Default)]
+struct SkidTrail {
+    wheels: [Option<Vec2>; 2],
+    forward_speed: f32,
+}
+
+/// The marks are despawned along with the rest of the level so any entities
+/// left in the pool are stale.
+fn clear_pool(mut pool: ResMut<SkidMarkPool>) {
+    pool.0.clear();
+}
+
+fn add_skid_trails(mut commands: Commands, cars: Query<Entity, (With<Racer>, Without<SkidTrail>)>) {
+    for car in cars.iter() {
+        commands.entity(car).insert(SkidTrail::default());
+    }
+}
+
+fn lay_skid_marks(
+    mut commands: Commands,
+    mut cars: Query<(
+        &physics::Velocity,
+        &physics::Angle,
+        &Transform,
+        &mut SkidTrail,
+    )>,
+    mut marks: Query<
+        (&mut SkidMark, &mut Sprite, &mut Transform, &mut Visibility),
+        Without<SkidTrail>,
+    >,
+    mut pool: ResMut<SkidMarkPool>,
+    asset_server: Res<AssetServer>,
+    time: Res<Time>,
+) {
+    let delta = time.delta_seconds();
+    if delta <= 0.0 {
+        return;
+    }
+
+    for (v, a, xform, mut trail) in cars.iter_mut() {
+        let forward = Vec2::from_angle(a.0);
+        let forward_speed = v.0.dot(forward);
+        let slip = v.0.dot(forward.perp()).abs();
+        let braking = (trail.forward_speed - forward_speed) / delta;
+        trail.forward_speed = forward_speed;
+
+        if slip < SLIP_THRESHOLD && braking < BRAKING_THRESHOLD {
+            trail.wheels = [None, None];
+            continue;
+        }
+
+        let pos = xform.translation.truncate();
+        for (wheel, offset) in trail.wheels.iter_mut().zip(REAR_WHEELS) {
+            let now = pos + forward.rotate(offset);
+            let Some(last) = *wheel else {
+                *wheel = Some(now);
+                continue;
+            };
+
+            let length = last.distance(now);
+            if length < SEGMENT_LENGTH {
+                continue;
+            }
+            *wheel = Some(now);
+            // The car has been moved (e.g. respawned) rather than skidded
+            if length > 4.0 * SEGMENT_LENGTH {
+                continue;
+            }
+
+            let transform = Transform {
+                translation: ((last + now) / 2.0).extend(SKIDMARK_Z),
+                rotation: Quat::from_rotation_z(Vec2::Y.angle_between(now - last)),
+                scale: Vec3::ONE,
+            };
+            let sprite = Sprite {
+                color: Color::WHITE,
+                custom_size: Some(vec2(12.0, length)),
+                // The texture shows the marks of both wheels; we only want one
+                rect: Some(Rect::new(0.0, 0.0, 12.0, 192.0)),
+                ..default()
+            };
+
+            if pool.0.len() >= MAX_SKIDMARKS {
+                if let Some(oldest) = pool.0.pop_front() {
+                    if let Ok((mut m, mut s, mut t, mut vis)) = marks.get_mut(oldest) {
+                        m.age = 0.0;
+                        *s = sprite;
+                        *t = transform;
+                        *vis = Visibility::Inherited;
+                        pool.0.push_back(oldest);
+                        continue;
+                    }
+                }
+            }
+
+            let mark = commands
+                .spawn((
+                    Name::new("SkidMark"),
+                    LevelComponent,
+                    SkidMark { age: 0.0 },
+                    SpriteBundle {
+                        sprite,
+                        transform,
+                        texture: asset_server.load(SKIDMARK_IMAGE),
+                        ..default()
+                    },
+                ))
+                .id();
+            pool.0.push_back(mark);
+        }
+    }
+}
+
+/// Fade out old skid marks. Faded marks are hidden rather than despawned so
+/// they can be reused.
+fn fade_skid_marks(
+    mut marks: Query<(&mut SkidMark, &mut Sprite, &mut Visibility)>,
+    time: Res<Time>,
+) {
+    let delta = time.delta_seconds();
+
+    for (mut mark, mut sprite, mut vis) in marks.iter_mut() {
+        if *vis == Visibility::Hidden {
+            continue;
+        }
+
+        mark.age += delta;
+        if mark.age >= FADE_TIME {
+            *vis = Visibility::Hidden;
+        } else {
+            sprite.color.set_a(1.0 - mark.age / FADE_TIME);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+//! Procedural engine sound.
+//!
+//! Rather than pitch shifting a sampled loop we synthesize the engine note
+//! directly from the engine speed (RPM) and load. Each car owns an
+//! [EngineSound] asset whose parameters are shared, via atomics, with the
+//! decoder running on the audio thread. The game updates the parameters
+//! every frame and the decoder smooths them sample-by-sample.
+//!
+//! The DSP lives in [EngineVoice], which has no dependencies on bevy, so it
+//! can be driven offline to render a buffer of samples.
+
+use bevy::{
+    audio::{Decodable, Source},
+    prelude::*,
+    reflect::TypePath,
+};
+use std::{
+    f32::consts::TAU,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+pub const SAMPLE_RATE: u32 = 22050;
+
+/// Number of cylinders. For a four-stroke engine each cylinder fires once
+/// every two revolutions.
+const CYLINDERS: f32 = 4.0;
+
+/// An f32 that can be shared between threads.
+#[derive(Debug, Default)]
+struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    fn new(v: f32) -> Self {
+        Self(AtomicU32::new(v.to_bits()))
+    }
+
+    fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, v: f32) {
+        self.0.store(v.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// The parameters of an engine voice.
+#[derive(Debug)]
+pub struct EngineParams {
+    rpm: AtomicF32,
+    load: AtomicF32,
+    gain: AtomicF32,
+    pitch: AtomicF32,
+    pan: AtomicF32,
+}
+
+impl Default for EngineParams {
+    fn default() -> Self {
+        Self {
+            rpm: AtomicF32::new(900.0),
+            load: AtomicF32::new(0.0),
+            gain: AtomicF32::new(0.0),
+            pitch: AtomicF32::new(1.0),
+            pan: AtomicF32::new(0.0),
+        }
+    }
+}
+
+impl EngineParams {
+    /// Engine speed in revolutions per minute.
+    pub fn set_rpm(&self, rpm: f32) {
+        self.rpm.set(rpm);
+    }
+
+    /// Engine load from 0.0 (coasting) to 1.0 (full throttle).
+    pub fn set_load(&self, load: f32) {
+        self.load.set(load.clamp(0.0, 1.0));
+    }
+
+    /// Overall gain, including any distance attenuation.
+    pub fn set_gain(&self, gain: f32) {
+        self.gain.set(gain.max(0.0));
+    }
+
+    /// Frequency multiplier, used to model the doppler effect.
+    pub fn set_pitch(&self, pitch: f32) {
+        self.pitch.set(pitch.clamp(0.5, 2.0));
+    }
+
+    /// Stereo position from -1.0 (left) to 1.0 (right).
+    pub fn set_pan(&self, pan: f32) {
+        self.pan.set(pan.clamp(-1.0, 1.0));
+    }
+}
+
+/// Engine sound asset, one per car.
+#[derive(Asset, TypePath, Debug, Default)]
+pub struct EngineSound {
+    pub params: Arc<EngineParams>,
+}
+
+impl Decodable for EngineSound {
+    type DecoderItem = f32;
+    type Decoder = EngineVoice;
+
+    fn decoder(&self) -> Self::Decoder {
+        EngineVoice::new(self.params.clone(), SAMPLE_RATE)
+    }
+}
+
+/// The engine synthesizer.
+///
+/// The voice generates interleaved stereo samples forever. The waveform is
+/// a train of firing pulses (a handful of harmonics of the firing
+/// frequency) plus some intake noise, both of which get brighter and louder
+/// as the load increases.
+#[derive(Debug)]
+pub struct EngineVoice {
+    params: Arc<EngineParams>,
+    sample_rate: u32,
+    phase: f32,
+    revolution: f32,
+    rpm: f32,
+    load: f32,
+    gain: f32,
+    pitch: f32,
+    pan: f32,
+    noise: u32,
+    lowpass: f32,
+    right: Option<f32>,
+}
+
+impl EngineVoice {
+    pub fn new(params: Arc<EngineParams>, sample_rate: u32) -> Self {
+        Self {
+            rpm: params.rpm.get(),
+            load: params.load.get(),
+            gain: params.gain.get(),
+            pitch: params.pitch.get(),
+            pan: params.pan.get(),
+            params,
+            sample_rate,
+            phase: 0.0,
+            revolution: 0.0,
+            noise: 0x1234_5678,
+            lowpass: 0.0,
+            right: None,
+        }
+    }
+
+    /// Generate the next (mono) sample.
+    fn next_mono(&mut self) -> f32 {
+        // One-pole smoothing (~10ms) of the parameters to avoid zipper noise
+        let k = 1.0 - (-1.0 / (0.01 * self.sample_rate as f32)).exp();
+        self.rpm += k * (self.params.rpm.get() - self.rpm);
+        self.load += k * (self.params.load.get() - self.load);
+        self.gain += k * (self.params.gain.get() - self.gain);
+        self.pitch += k * (self.params.pitch.get() - self.pitch);
+        self.pan += k * (self.params.pan.get() - self.pan);
+
+        let revs = self.rpm / 60.0 * self.pitch;
+        let firing = revs * CYLINDERS / 2.0;
+        self.phase = (self.phase + firing / self.sample_rate as f32).fract();
+        self.revolution = (self.revolution + revs / self.sample_rate as f32).fract();
+
+        // Sharper pulses (more harmonics) when the engine is working hard
+        let harmonics = 3 + (5.0 * self.load) as usize;
+        let mut pulse = 0.0;
+        for h in 1..=harmonics {
+            pulse += (TAU * h as f32 * self.phase).sin() / h as f32;
+        }
+        // Each revolution is slightly uneven, which stops the note sounding
+        // like a pure buzz
+        pulse += 0.5 * (TAU * self.revolution).sin();
+
+        // xorshift noise, low pass filtered to sound like intake roar
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        let white = (self.noise as f32 / u32::MAX as f32) * 2.0 - 1.0;
+        self.lowpass += 0.2 * (white - self.lowpass);
+
+        let level = 0.5 + 0.5 * self.load;
+        self.gain * level * 0.25 * (pulse + self.load * self.lowpass)
+    }
+}
+
+impl Iterator for EngineVoice {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(right) = self.right.take() {
+            return Some(right);
+        }
+
+        // Constant power panning
+        let sample = self.next_mono();
+        let angle = (self.pan + 1.0) * std::f32::consts::FRAC_PI_4;
+        self.right = Some(sample * angle.sin());
+        Some(sample * angle.cos())
+    }
+}
+
+impl Source for EngineVoice {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 44100;
+
+    fn voice(rpm: f32, load: f32, gain: f32) -> EngineVoice {
+        let params = Arc::new(EngineParams::default());
+        params.set_rpm(rpm);
+        params.set_load(load);
+        params.set_gain(gain);
+        EngineVoice::new(params, RATE)
+    }
+
+    /// Render `seconds` of the left channel.
+    fn render(voice: EngineVoice, seconds: f32) -> Vec<f32> {
+        let n = (seconds * RATE as f32) as usize;
+        voice.step_by(2).take(n).collect()
+    }
+
+    fn zero_crossings(samples: &[f32]) -> usize {
+        samples
+            .windows(2)
+            .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+            .count()
+    }
+
+    #[test]
+    fn output_is_in_range() {
+        for (rpm, load) in [(900.0, 0.0), (4000.0, 0.5), (7000.0, 1.0)] {
+            let params = Arc::new(EngineParams::default());
+            params.set_rpm(rpm);
+            params.set_load(load);
+            params.set_gain(1.0);
+            params.set_pitch(2.0);
+            let voice = EngineVoice::new(params, RATE);
+            for sample in voice.take(2 * RATE as usize) {
+                assert!((-1.0..=1.0).contains(&sample), "{sample} out of range");
+            }
+        }
+    }
+
+    #[test]
+    fn silent_at_zero_gain() {
+        let samples = render(voice(3000.0, 1.0, 0.0), 0.5);
+        assert!(samples.iter().all(|s| *s == 0.0));
+    }
+
+    #[test]
+    fn pitch_follows_rpm() {
+        let slow = zero_crossings(&render(voice(2000.0, 0.0, 1.0), 1.0));
+        let fast = zero_crossings(&render(voice(4000.0, 0.0, 1.0), 1.0));
+        assert!(slow > 0);
+        let ratio = fast as f32 / slow as f32;
+        assert!((1.8..2.2).contains(&ratio), "{slow} vs {fast} crossings");
+    }
+}
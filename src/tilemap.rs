@@ -30,7 +30,7 @@ use bevy::{
     utils::{BoxedFuture, HashMap},
 };
 use bevy_ecs_tilemap::prelude::*;
-use crate::LevelComponent;
+use crate::{track, LevelComponent};
 
 use thiserror::Error;
 
@@ -48,6 +48,7 @@ impl Plugin for TiledMapPlugin {
 #[derive(TypePath, Asset, Debug)]
 pub struct TiledMap {
     pub map: tiled::Map,
+    pub track: track::Track,
     pub tilemap_textures: HashMap<usize, TilemapTexture>,
     pub tile_image_offsets: HashMap<(usize, tiled::TileId), u32>,
 }
@@ -95,6 +96,9 @@ pub enum TiledAssetLoaderError {
     /// An [IO](std::io) Error
     #[error("Could not load Tiled file: {0}")]
     Io(#[from] std::io::Error),
+    /// The map has no usable track layer
+    #[error("Could not load Tiled file: {0}")]
+    Track(#[from] track::TrackError),
 }
 
 impl AssetLoader for TiledLoader {
@@ -119,6 +123,7 @@ impl AssetLoader for TiledLoader {
             let map = loader.load_tmx_map(load_context.path()).map_err(|e| {
                 std::io::Error::new(ErrorKind::Other, format!("Could not load TMX map: {e}"))
             })?;
+            let track = track::Track::from_map(&map)?;
 
             let mut tilemap_textures = HashMap::default();
             #[cfg(not(feature = "atlas"))]
@@ -164,6 +169,7 @@ impl AssetLoader for TiledLoader {
 
             let asset_map = TiledMap {
                 map,
+                track,
                 tilemap_textures,
                 tile_image_offsets,
             };
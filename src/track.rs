@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+//! Find the track in a level.
+//!
+//! The track is any tile layer named "Track", or any tile layer with a
+//! `track` property set to true. A level may split the track across several
+//! layers (e.g. to keep the pit lane separate) in which case the layers are
+//! merged. Setting `track = false` excludes a layer even if it is named
+//! "Track".
+//!
+//! Like [crate::guidance], this module is included directly by the
+//! benchmarks so it must only depend on [crate::util].
+
+use thiserror::Error;
+
+use crate::util;
+
+/// Name given to the track layer by the level designer.
+const TRACK_LAYER_NAME: &str = "Track";
+
+#[derive(Debug, Error)]
+pub enum TrackError {
+    #[error("Map has no track layer (name a tile layer \"{TRACK_LAYER_NAME}\" or give it a `track = true` property)")]
+    NoTrackLayer,
+    #[error("Track layer \"{0}\" has no fixed size (infinite maps are not supported)")]
+    Unbounded(String),
+}
+
+/// The tiles that make up the track, merged from every track layer.
+#[derive(Clone, Debug)]
+pub struct Track {
+    pub width: usize,
+    pub height: usize,
+    /// One entry per tile, row by row, set if the tile is part of the track.
+    tiles: Vec<bool>,
+}
+
+impl Track {
+    pub fn from_map(map: &tiled::Map) -> Result<Self, TrackError> {
+        let (width, height) = (map.width as usize, map.height as usize);
+        let mut tiles = vec![false; width * height];
+        let mut found = false;
+
+        for layer in map.layers().filter(is_track_layer) {
+            let Some(tiles_layer) = layer.as_tile_layer() else {
+                continue;
+            };
+            if tiles_layer.width().is_none() || tiles_layer.height().is_none() {
+                return Err(TrackError::Unbounded(layer.name.clone()));
+            }
+
+            found = true;
+            for y in 0..height {
+                for x in 0..width {
+                    tiles[y * width + x] |= tiles_layer.get_tile(x as i32, y as i32).is_some();
+                }
+            }
+        }
+
+        if !found {
+            return Err(TrackError::NoTrackLayer);
+        }
+        Ok(Self {
+            width,
+            height,
+            tiles,
+        })
+    }
+
+    /// Check whether the tile at `(x, y)` is part of the track. Anything
+    /// beyond the edge of the map is not.
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= 0
+            && y >= 0
+            && (x as usize) < self.width
+            && (y as usize) < self.height
+            && self.tiles[y as usize * self.width + x as usize]
+    }
+}
+
+fn is_track_layer(layer: &tiled::Layer) -> bool {
+    util::bool_property(&layer.properties, "track").unwrap_or(layer.name == TRACK_LAYER_NAME)
+}
@@ -38,3 +38,26 @@ pub fn despawn_entities<T: Component>(to_despawn: Query<Entity, With<T>>, mut co
         commands.entity(entity).despawn_recursive();
     }
 }
+
+/// Look up a numeric property from the tiled data.
+///
+/// Designers don't always pick the right type for a property so integers
+/// and strings that can be parsed are accepted too.
+pub fn float_property(props: &tiled::Properties, name: &str) -> Option<f32> {
+    match props.get(name)? {
+        tiled::PropertyValue::FloatValue(v) => Some(*v),
+        tiled::PropertyValue::IntValue(v) => Some(*v as f32),
+        tiled::PropertyValue::StringValue(v) => v.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Look up a boolean property from the tiled data.
+pub fn bool_property(props: &tiled::Properties, name: &str) -> Option<bool> {
+    match props.get(name)? {
+        tiled::PropertyValue::BoolValue(v) => Some(*v),
+        tiled::PropertyValue::IntValue(v) => Some(*v != 0),
+        tiled::PropertyValue::StringValue(v) => v.trim().parse().ok(),
+        _ => None,
+    }
+}
@@ -0,0 +1,413 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright (C) 2024 Daniel Thompson
+
+//! Check a level for mistakes that would spoil the race.
+//!
+//! Run with `tdr2024 validate <level.tmx>`. Problems are reported in the
+//! same `file:line: error: message` form as a compiler so that editors (and
+//! CI logs) can point straight at the offending object. Anything that works
+//! but looks like a mistake is reported as a warning, which doesn't fail.
+
+use bevy::math::{vec2, Vec2};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::Path,
+};
+
+use crate::{geometry::Polygon, objectmap, track::Track};
+
+/// Spacing (in world units) of the points used to find the tiles under a
+/// checkpoint.
+const SAMPLE_SPACING: f32 = 16.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Severity {
+    /// Something that would spoil the race
+    Error,
+    /// Something that works but is probably a mistake
+    Warning,
+}
+
+struct Diagnostic {
+    line: usize,
+    severity: Severity,
+    message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {severity}: {}", self.line, self.message)
+    }
+}
+
+/// An object from the level, along with the line it was defined on.
+struct Placed {
+    line: usize,
+    polygon: Polygon,
+}
+
+type Tile = (i32, i32);
+
+/// Check the level and report any problems. Returns the exit code, which
+/// is only non-zero if there are errors (warnings alone don't fail).
+pub fn validate(path: &Path) -> i32 {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("{}: error: {e}", path.display());
+            return 2;
+        }
+    };
+    let map = match tiled::Loader::new().load_tmx_map(path) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("{}: error: {e}", path.display());
+            return 2;
+        }
+    };
+
+    let mut diagnostics = check(&map, &text);
+    diagnostics.sort_by_key(|d| d.line);
+    for d in &diagnostics {
+        eprintln!("{}:{d}", path.display());
+    }
+
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+    if errors == 0 {
+        println!("{}: OK", path.display());
+        0
+    } else {
+        eprintln!("{}: {errors} problem(s) found", path.display());
+        1
+    }
+}
+
+fn check(map: &tiled::Map, text: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    // tiled doesn't tell us where anything came from so we have to find
+    // the line numbers for ourselves.
+    let map_line = find_line(text, "<map ").unwrap_or(1);
+    let object_lines = text
+        .lines()
+        .enumerate()
+        .filter_map(|(n, line)| {
+            let id = line.trim_start().strip_prefix("<object id=\"")?;
+            let id = id.split('"').next()?.parse::<u32>().ok()?;
+            Some((id, n + 1))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut players = 0;
+    let mut cars = Vec::new();
+    let mut scenery = Vec::new();
+    let mut checkpoints = Vec::new();
+
+    for layer in map.layers().filter_map(|layer| layer.as_object_layer()) {
+        for obj in layer.objects() {
+            let line = object_lines.get(&obj.id()).copied().unwrap_or(map_line);
+
+            let Some(tile_data) = obj.tile_data() else {
+                if obj.name == "ai_line" {
+                    continue;
+                }
+                if objectmap::is_hazard_zone(&obj) {
+                    if !matches!(
+                        obj.shape,
+                        tiled::ObjectShape::Rect { .. } | tiled::ObjectShape::Ellipse { .. }
+                    ) {
+                        error(
+                            &mut diagnostics,
+                            line,
+                            format!("unsupported hazard shape: {:?}", obj.shape),
+                        );
+                    }
+                    continue;
+                }
+                match obj.shape {
+                    tiled::ObjectShape::Rect { width, height } if width > 0.0 && height > 0.0 => {
+                        let sz = vec2(width, height);
+                        checkpoints.push((
+                            objectmap::checkpoint_order(&obj),
+                            Placed {
+                                line,
+                                polygon: Polygon::from_vec(&sz)
+                                    .transform(&objectmap::shape_transform(map, &obj, sz)),
+                            },
+                        ));
+                    }
+                    _ => error(
+                        &mut diagnostics,
+                        line,
+                        format!("unsupported shape for \"{}\": {:?}", obj.name, obj.shape),
+                    ),
+                }
+                continue;
+            };
+
+            let tiled::TilesetLocation::Map(tileset) = tile_data.tileset_location() else {
+                error(
+                    &mut diagnostics,
+                    line,
+                    "tile isn't using a map ID as the tileset location",
+                );
+                continue;
+            };
+            let image = map.tilesets()[*tileset]
+                .get_tile(tile_data.id())
+                .and_then(|tile| tile.image.clone());
+            let Some(image) = image else {
+                error(&mut diagnostics, line, "tile image missing from tile data");
+                continue;
+            };
+            let Some(img_src) = image.source.to_str() else {
+                error(&mut diagnostics, line, "cannot convert image name");
+                continue;
+            };
+            if objectmap::is_hazard(img_src) {
+                continue;
+            }
+
+            let sz = vec2(image.width as f32, image.height as f32);
+            let placed = Placed {
+                line,
+                polygon: objectmap::collision_polygon(img_src, sz)
+                    .transform(&objectmap::tile_transform(map, &obj, sz)),
+            };
+            if objectmap::is_car(img_src) {
+                players += objectmap::is_player(img_src) as usize;
+                cars.push(placed);
+            } else {
+                scenery.push(placed);
+            }
+        }
+    }
+
+    if players == 0 {
+        error(
+            &mut diagnostics,
+            map_line,
+            "no player car (the player drives the red car)",
+        );
+    }
+
+    for car in &cars {
+        for obj in scenery
+            .iter()
+            .filter(|obj| car.polygon.is_touching(&obj.polygon))
+        {
+            error(
+                &mut diagnostics,
+                car.line,
+                format!("car starts overlapping the scenery on line {}", obj.line),
+            );
+        }
+    }
+
+    // Put the checkpoints in the order they must be driven
+    checkpoints.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    for (n, (order, c)) in checkpoints.iter().enumerate() {
+        if order.is_infinite() {
+            // This is allowed (such checkpoints are driven in the order they
+            // were drawn, after the numbered ones) but it is easy to get wrong
+            warning(
+                &mut diagnostics,
+                c.line,
+                "checkpoint has no `order` property (it will be driven after the numbered \
+                 checkpoints, in the order they were drawn)",
+            );
+        } else if n > 0 && checkpoints[n - 1].0 == *order {
+            error(
+                &mut diagnostics,
+                c.line,
+                format!(
+                    "checkpoint has the same order as the checkpoint on line {}",
+                    checkpoints[n - 1].1.line
+                ),
+            );
+        }
+    }
+    let checkpoints = checkpoints.into_iter().map(|(_, c)| c).collect::<Vec<_>>();
+
+    match checkpoints.len() {
+        0 => error(
+            &mut diagnostics,
+            map_line,
+            "no checkpoints (draw lap_box rectangles across the track)",
+        ),
+        1 => error(
+            &mut diagnostics,
+            checkpoints[0].line,
+            "only one checkpoint (a lap needs at least two)",
+        ),
+        _ => (),
+    }
+
+    let track = match Track::from_map(map) {
+        Ok(track) => track,
+        Err(e) => {
+            error(&mut diagnostics, map_line, e.to_string());
+            return diagnostics;
+        }
+    };
+
+    let covered = checkpoints
+        .iter()
+        .map(|c| covered_tiles(map, &track, &c.polygon))
+        .collect::<Vec<_>>();
+    let mut all_on_track = true;
+    for (c, tiles) in checkpoints.iter().zip(&covered) {
+        if tiles.is_empty() {
+            error(
+                &mut diagnostics,
+                c.line,
+                "checkpoint doesn't overlap the track",
+            );
+            all_on_track = false;
+        }
+    }
+    if !all_on_track || checkpoints.len() < 2 {
+        return diagnostics;
+    }
+
+    // Every checkpoint must be reachable from the first one...
+    let reachable = flood(&track, &covered[0], &HashSet::new());
+    let mut connected = true;
+    for (c, tiles) in checkpoints.iter().zip(&covered).skip(1) {
+        if tiles.is_disjoint(&reachable) {
+            error(
+                &mut diagnostics,
+                c.line,
+                format!(
+                    "checkpoint isn't connected to the checkpoint on line {}",
+                    checkpoints[0].line
+                ),
+            );
+            connected = false;
+        }
+    }
+    if !connected {
+        return diagnostics;
+    }
+
+    // ... and, because the track is a loop, they must still be reachable
+    // from each other when any one checkpoint is blocked off (there is
+    // always the other way round).
+    for (n, (c, blocked)) in checkpoints.iter().zip(&covered).enumerate() {
+        let others = covered
+            .iter()
+            .enumerate()
+            .filter(|(m, _)| *m != n)
+            .map(|(_, tiles)| tiles - blocked)
+            .filter(|tiles| !tiles.is_empty())
+            .collect::<Vec<_>>();
+        let Some(start) = others.first() else {
+            continue;
+        };
+        let reachable = flood(&track, start, blocked);
+        if others.iter().any(|tiles| tiles.is_disjoint(&reachable)) {
+            error(
+                &mut diagnostics,
+                c.line,
+                "track doesn't loop back to this checkpoint (cars would have to turn round)",
+            );
+        }
+    }
+
+    // Finally, it must be possible to drive from each checkpoint to the next
+    // one without passing any of the others.
+    for (n, c) in checkpoints.iter().enumerate() {
+        let next = (n + 1) % checkpoints.len();
+        let blocked = covered
+            .iter()
+            .enumerate()
+            .filter(|(m, _)| *m != n && *m != next)
+            .flat_map(|(_, tiles)| tiles.iter().copied())
+            .collect::<HashSet<_>>();
+        let reachable = flood(&track, &(&covered[n] - &blocked), &blocked);
+        if covered[next].is_disjoint(&reachable) {
+            error(
+                &mut diagnostics,
+                checkpoints[next].line,
+                format!(
+                    "checkpoint can't be reached from the checkpoint on line {} without \
+                     passing another one (check the `order` properties)",
+                    c.line
+                ),
+            );
+        }
+    }
+
+    diagnostics
+}
+
+fn error(diagnostics: &mut Vec<Diagnostic>, line: usize, message: impl Into<String>) {
+    diagnostics.push(Diagnostic {
+        line,
+        severity: Severity::Error,
+        message: message.into(),
+    });
+}
+
+fn warning(diagnostics: &mut Vec<Diagnostic>, line: usize, message: impl Into<String>) {
+    diagnostics.push(Diagnostic {
+        line,
+        severity: Severity::Warning,
+        message: message.into(),
+    });
+}
+
+fn find_line(text: &str, needle: &str) -> Option<usize> {
+    text.lines()
+        .position(|line| line.contains(needle))
+        .map(|n| n + 1)
+}
+
+/// Convert a world position into the tile it is on.
+fn to_tile(map: &tiled::Map, pos: Vec2) -> Tile {
+    (
+        (pos.x / map.tile_width as f32 + map.width as f32 / 2.0).floor() as i32,
+        (-pos.y / map.tile_height as f32 + map.height as f32 / 2.0).floor() as i32,
+    )
+}
+
+/// Find the track tiles underneath a polygon.
+fn covered_tiles(map: &tiled::Map, track: &Track, polygon: &Polygon) -> HashSet<Tile> {
+    let (min, max) = polygon
+        .iter()
+        .fold((Vec2::MAX, Vec2::MIN), |(min, max), pt| {
+            (min.min(*pt), max.max(*pt))
+        });
+    let steps = ((max - min) / SAMPLE_SPACING).ceil();
+
+    (0..steps.y as i32)
+        .flat_map(|y| (0..steps.x as i32).map(move |x| (x, y)))
+        .map(|(x, y)| min + (vec2(x as f32, y as f32) + 0.5) * SAMPLE_SPACING)
+        .filter(|pt| polygon.contains_point(*pt))
+        .map(|pt| to_tile(map, pt))
+        .filter(|&(x, y)| track.contains(x, y))
+        .collect()
+}
+
+/// Find every track tile that can be reached from `start` without driving
+/// through any of the `blocked` tiles.
+fn flood(track: &Track, start: &HashSet<Tile>, blocked: &HashSet<Tile>) -> HashSet<Tile> {
+    let mut reached = HashSet::new();
+    let mut todo = start.iter().copied().collect::<Vec<_>>();
+
+    while let Some((x, y)) = todo.pop() {
+        if !track.contains(x, y) || blocked.contains(&(x, y)) || !reached.insert((x, y)) {
+            continue;
+        }
+        todo.extend([(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)]);
+    }
+    reached
+}
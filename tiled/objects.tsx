@@ -8,12 +8,20 @@
   <image width="165" height="99" source="../src/assets/kenney_racing-pack/PNG/Objects/arrow_yellow.png"/>
  </tile>
  <tile id="2">
+  <properties>
+   <property name="dynamic" type="bool" value="true"/>
+   <property name="mass" type="float" value="0.3"/>
+  </properties>
   <image width="56" height="56" source="../src/assets/kenney_racing-pack/PNG/Objects/barrel_blue.png"/>
  </tile>
  <tile id="3">
   <image width="70" height="48" source="../src/assets/kenney_racing-pack/PNG/Objects/barrel_blue_down.png"/>
  </tile>
  <tile id="4">
+  <properties>
+   <property name="dynamic" type="bool" value="true"/>
+   <property name="mass" type="float" value="0.3"/>
+  </properties>
   <image width="56" height="56" source="../src/assets/kenney_racing-pack/PNG/Objects/barrel_red.png"/>
  </tile>
  <tile id="5">
@@ -35,6 +43,10 @@
   <image width="46" height="44" source="../src/assets/kenney_racing-pack/PNG/Objects/cone_down.png"/>
  </tile>
  <tile id="11">
+  <properties>
+   <property name="dynamic" type="bool" value="true"/>
+   <property name="mass" type="float" value="0.05"/>
+  </properties>
   <image width="45" height="44" source="../src/assets/kenney_racing-pack/PNG/Objects/cone_straight.png"/>
  </tile>
  <tile id="12">
@@ -89,15 +101,31 @@
   <image width="256" height="256" source="../src/assets/kenney_racing-pack/PNG/Objects/tent_red_large.png"/>
  </tile>
  <tile id="29">
+  <properties>
+   <property name="dynamic" type="bool" value="true"/>
+   <property name="mass" type="float" value="0.5"/>
+  </properties>
   <image width="56" height="56" source="../src/assets/kenney_racing-pack/PNG/Objects/tires_red.png"/>
  </tile>
  <tile id="30">
+  <properties>
+   <property name="dynamic" type="bool" value="true"/>
+   <property name="mass" type="float" value="0.5"/>
+  </properties>
   <image width="56" height="56" source="../src/assets/kenney_racing-pack/PNG/Objects/tires_red_alt.png"/>
  </tile>
  <tile id="31">
+  <properties>
+   <property name="dynamic" type="bool" value="true"/>
+   <property name="mass" type="float" value="0.5"/>
+  </properties>
   <image width="56" height="56" source="../src/assets/kenney_racing-pack/PNG/Objects/tires_white.png"/>
  </tile>
  <tile id="32">
+  <properties>
+   <property name="dynamic" type="bool" value="true"/>
+   <property name="mass" type="float" value="0.5"/>
+  </properties>
   <image width="56" height="56" source="../src/assets/kenney_racing-pack/PNG/Objects/tires_white_alt.png"/>
  </tile>
  <tile id="33">